@@ -0,0 +1,108 @@
+//! The `--cache` store: the results of a recursive search, file by file, so
+//! running the same search again only reads the files that changed since.
+//!
+//! Each search gets its own file in the cache directory, named after a hash
+//! of everything that decides its results: the query and the options that
+//! affect matching. Inside, each file searched is stamped with its
+//! modification time and size, and its results are reused only while both
+//! are unchanged.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, FileMatch};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    /// What the results were found with; a cache file written for a
+    /// different search, should two ever share a hash, is ignored.
+    signature: String,
+    files: HashMap<PathBuf, Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    stamp: Stamp,
+    found: Vec<FileMatch>,
+}
+
+/// A file's modification time, in nanoseconds since the epoch, and size.
+type Stamp = (u128, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((modified.as_nanos(), metadata.len()))
+}
+
+/// `$XDG_CACHE_HOME/minigrep`, or `~/.cache/minigrep`, or else a directory
+/// under the system's temporary directory.
+pub fn default_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+                                 .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                                 .unwrap_or_else(env::temp_dir)
+                                 .join("minigrep")
+}
+
+/// Everything that decides which lines a recursive search finds, and what
+/// it records about them.
+pub fn signature(config: &Config, details: bool) -> String {
+    format!(
+        "{:?}",
+        (
+            (&config.query, &config.patterns, config.regex, config.case_sensitive, config.invert, &config.and_not),
+            (config.field, config.delimiter, config.max_line_bytes, &config.line_ranges, &config.script),
+            (&config.within_tag, &config.section_start, &config.section_end, &config.context_boundary),
+            (config.no_comments, &config.comment_prefix, config.all_patterns, config.overlapping, config.no_unicode),
+            (config.min_line_length, config.max_line_length, config.line_length_bytes),
+            (config.multiline, config.byte_mode, config.context(), details),
+        )
+    )
+}
+
+/// Where the results of the search with `signature` are kept in `dir`.
+pub fn file_for(dir: &Path, signature: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    signature.hash(&mut hasher);
+    dir.join(format!("{:016x}.json", hasher.finish()))
+}
+
+impl Cache {
+    /// The cache in `path`, or an empty one if there's none yet, it can't
+    /// be read, or it was written for another search.
+    pub fn load(path: &Path, signature: &str) -> Cache {
+        fs::read_to_string(path).ok()
+                                .and_then(|contents| serde_json::from_str::<Cache>(&contents).ok())
+                                .filter(|cache| cache.signature == signature)
+                                .unwrap_or_else(|| Cache { signature: signature.to_string(), ..Cache::default() })
+    }
+
+    /// The results recorded for `path`, if it hasn't changed since.
+    pub fn get(&self, path: &Path) -> Option<Vec<FileMatch>> {
+        let entry = self.files.get(path)?;
+        (Some(entry.stamp) == stamp(path)).then(|| entry.found.clone())
+    }
+
+    pub fn insert(&mut self, path: &Path, found: Vec<FileMatch>) {
+        if let Some(stamp) = stamp(path) {
+            self.files.insert(path.to_path_buf(), Entry { stamp, found });
+        }
+    }
+
+    /// Writes the cache to `path`, leaving out files that no longer exist.
+    pub fn save(mut self, path: &Path) -> io::Result<()> {
+        self.files.retain(|file, _| file.exists());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(&self)?)
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(ConfigError),
+    Io(io::Error),
+    Regex(regex::Error),
+    /// The search was interrupted before every file was searched.
+    Cancelled { files_searched: usize },
+    /// `--max-runtime` ran out before every file was searched.
+    TimedOut { files_searched: usize },
+}
+
+impl Error {
+    /// A stable, machine-readable name for the variant, used by
+    /// `--error-format=json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Io(_) => "io",
+            Error::Regex(_) => "regex",
+            Error::Cancelled { .. } => "cancelled",
+            Error::TimedOut { .. } => "timed_out",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"error\": \"{}\", \"kind\": \"{}\"}}",
+            json_escape(&self.to_string()),
+            self.kind()
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "{}", msg),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Regex(err) => write!(f, "{}", err),
+            Error::Cancelled { files_searched } => {
+                write!(f, "Interrupted after searching {} files", files_searched)
+            }
+            Error::TimedOut { files_searched } => {
+                write!(f, "Ran out of time after searching {} files", files_searched)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Error {
+        Error::Config(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Error {
+        Error::Regex(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Invalid(&'static str),
+    ConflictingOptions(String),
+    /// A `.minigreprc` that couldn't be read or parsed.
+    ConfigFile(String),
+    /// A `${VAR}` in the query naming an unset variable, with
+    /// `--expand-env=error`.
+    UndefinedVariable(String),
+    /// A `--script` that doesn't parse or type-check.
+    InvalidScript(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+            ConfigError::ConflictingOptions(msg) => write!(f, "Conflicting options: {}", msg),
+            ConfigError::ConfigFile(msg) => write!(f, "Invalid config file {}", msg),
+            ConfigError::UndefinedVariable(name) => write!(f, "Environment variable {} isn't set", name),
+            ConfigError::InvalidScript(msg) => write!(f, "Invalid --script: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<&'static str> for ConfigError {
+    fn from(msg: &'static str) -> ConfigError {
+        ConfigError::Invalid(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(format: &str) -> Result<ErrorFormat, &'static str> {
+        match format {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err("Unknown --error-format value"),
+        }
+    }
+
+    /// Picks the format straight from the raw arguments, so errors raised
+    /// while parsing the rest of the `Config` are reported in it too.
+    pub fn from_args(args: &[String]) -> ErrorFormat {
+        args.iter()
+            .rev()
+            .filter_map(|arg| arg.strip_prefix("--error-format="))
+            .find_map(|format| ErrorFormat::parse(format).ok())
+            .unwrap_or(ErrorFormat::Text)
+    }
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
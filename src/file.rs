@@ -0,0 +1,523 @@
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec;
+
+/// How many leading bytes are sniffed for a NUL when deciding whether a file
+/// is binary.
+const SNIFF_LEN: u64 = 1024;
+
+/// How long a FIFO named on the command line is given to get a writer before
+/// it's reported rather than waited on forever, when there's no
+/// `--max-runtime` to go by.
+const FIFO_WAIT: Duration = Duration::from_secs(2);
+
+/// Reads a UTF-8 file, dropping a leading byte-order mark.
+pub fn read_file(path: &Path) -> io::Result<String> {
+    let mut contents = read_file_with_bom(path)?;
+    strip_bom(&mut contents);
+    Ok(contents)
+}
+
+fn read_file_with_bom(path: &Path) -> io::Result<String> {
+    let mut f = open_decompressed(path)?;
+
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)?;
+
+    Ok(contents)
+}
+
+/// Reads `filename`, or standard input when it's `-`. Unlike `read_file`,
+/// this keeps any byte-order mark, for the caller to decide about. A FIFO
+/// is read within `budget`, as `read_fifo` describes.
+pub fn read_input(filename: &str, budget: Option<Duration>) -> io::Result<String> {
+    open_source(Path::new(filename)).read(budget)
+}
+
+/// What a name given on the command line refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Standard input, named `-`.
+    Stdin,
+    /// A directory, whose files `-r` searches.
+    Dir(PathBuf),
+    /// A compressed file, decompressed as it's read: `.bz2` with the `bzip2`
+    /// feature, or `.xz` with the `xz` feature.
+    Archive(PathBuf),
+    /// Any other file, read as it is.
+    File(PathBuf),
+}
+
+/// Sorts `path` into the kind of input it is. Nothing is opened yet, so a
+/// path that doesn't exist is a `File` whose error comes when it's read.
+pub fn open_source(path: &Path) -> Source {
+    if path == Path::new("-") {
+        Source::Stdin
+    } else if path.is_dir() {
+        Source::Dir(path.to_path_buf())
+    } else if is_compressed(path) {
+        Source::Archive(path.to_path_buf())
+    } else {
+        Source::File(path.to_path_buf())
+    }
+}
+
+impl Source {
+    /// Reads all of the source as UTF-8 text, keeping any byte-order mark.
+    /// A directory can't be read this way; its files must be walked. A FIFO
+    /// is read within `budget`, as `read_fifo` describes.
+    pub fn read(&self, budget: Option<Duration>) -> io::Result<String> {
+        match self {
+            Source::Stdin => {
+                let mut contents = String::new();
+                io::stdin().read_to_string(&mut contents)?;
+                Ok(contents)
+            }
+            Source::Dir(path) => Err(is_a_directory(path)),
+            Source::Archive(path) | Source::File(path) => {
+                check_searchable(path)?;
+                let mut contents = String::new();
+                open_named(path, budget)?.read_to_string(&mut contents)?;
+                Ok(contents)
+            }
+        }
+    }
+
+    /// Like `read`, but without requiring the contents to be valid UTF-8.
+    pub fn read_bytes(&self, budget: Option<Duration>) -> io::Result<Vec<u8>> {
+        match self {
+            Source::Stdin => {
+                let mut contents = Vec::new();
+                io::stdin().read_to_end(&mut contents)?;
+                Ok(contents)
+            }
+            Source::Dir(path) => Err(is_a_directory(path)),
+            Source::Archive(path) | Source::File(path) => {
+                check_searchable(path)?;
+                let mut contents = Vec::new();
+                open_named(path, budget)?.read_to_end(&mut contents)?;
+                Ok(contents)
+            }
+        }
+    }
+}
+
+/// Opens a file named on the command line as `open_decompressed` does, or
+/// reads a FIFO whole with `read_fifo`.
+fn open_named(path: &Path, budget: Option<Duration>) -> io::Result<Box<dyn Read>> {
+    if is_fifo(path) {
+        return Ok(Box::new(io::Cursor::new(read_fifo(path, budget)?)));
+    }
+    open_decompressed(path)
+}
+
+/// What the thread reading a FIFO reports back.
+enum FifoRead {
+    /// A writer opened the FIFO, so reading has begun.
+    Opened,
+    /// The FIFO was read to the end, or couldn't be opened or read.
+    Done(io::Result<Vec<u8>>),
+}
+
+/// Reads the FIFO at `path` to the end on a thread of its own, since opening
+/// it blocks until something opens it for writing, and reading blocks until
+/// that writer closes it. With a `budget`, both must happen within it;
+/// without one, a writer is waited for only `FIFO_WAIT`, then read for as
+/// long as it keeps writing. A thread given up on is left blocked, and ends
+/// with the process.
+fn read_fifo(path: &Path, budget: Option<Duration>) -> io::Result<Vec<u8>> {
+    let started = Instant::now();
+    let (report, reports) = mpsc::channel();
+    let reading = path.to_path_buf();
+    thread::spawn(move || {
+        let read = File::open(reading).and_then(|mut file| {
+            let _ = report.send(FifoRead::Opened);
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).map(|_| contents)
+        });
+        let _ = report.send(FifoRead::Done(read));
+    });
+    let timed_out = |what: &str, wait: Duration| {
+        io::Error::new(io::ErrorKind::TimedOut, format!("{} is a named pipe {} {}s", path.display(), what, wait.as_secs_f64()))
+    };
+
+    let wait = budget.unwrap_or(FIFO_WAIT);
+    match reports.recv_timeout(wait) {
+        Ok(FifoRead::Opened) => {}
+        Ok(FifoRead::Done(read)) => return read,
+        Err(_) => return Err(timed_out("that nothing wrote to within", wait)),
+    }
+    let done = match budget {
+        Some(budget) => reports.recv_timeout(budget.saturating_sub(started.elapsed())).ok(),
+        None => reports.recv().ok(),
+    };
+    match done {
+        Some(FifoRead::Done(read)) => read,
+        _ => Err(timed_out("still being written to after", wait)),
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+fn is_a_directory(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::IsADirectory, format!("{} is a directory; search it with -r", path.display()))
+}
+
+/// Refuses special files that can't be read as a stream of text: sockets and
+/// block devices. FIFOs and character devices, such as `/dev/stdin` or a
+/// shell's `<(command)`, are read like standard input, until the writer
+/// closes them, though a FIFO is only waited on so long, as `read_fifo`
+/// describes. Recursive searches skip every special file without asking.
+#[cfg(unix)]
+fn check_searchable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = fs::metadata(path)?.file_type();
+    let kind = if file_type.is_socket() {
+        "a socket"
+    } else if file_type.is_block_device() {
+        "a block device"
+    } else {
+        return Ok(());
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is {}, which minigrep can't search", path.display(), kind),
+    ))
+}
+
+#[cfg(not(unix))]
+fn check_searchable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The device and inode of the file at `path`, which are the same for every
+/// hard or symbolic link to it. `None` if the file can't be inspected, and
+/// always off Unix.
+#[cfg(unix)]
+pub fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether `a` and `b` are the same existing file, even when reached
+/// through different links.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (file_id(a), file_id(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b),
+    }
+}
+
+/// Writes `contents` to `path`, replacing any file there. Errors name the
+/// path.
+pub fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::write(path, contents).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+/// Removes a byte-order mark from the start of `contents`, which would
+/// otherwise be an invisible first character of the first line. Text decoded
+/// from UTF-16 keeps its byte-order mark the same way, so this covers that
+/// too.
+pub fn strip_bom(contents: &mut String) {
+    if contents.starts_with('\u{feff}') {
+        contents.drain(..'\u{feff}'.len_utf8());
+    }
+}
+
+/// `text` with every newline replaced by `terminator` and every `terminator`
+/// by a newline, so records ending in `terminator` can be searched as lines.
+/// `terminator` must be ASCII, which keeps the text valid UTF-8.
+pub fn swap_newlines(text: &str, terminator: u8) -> String {
+    let swapped = text.bytes().map(|b| swap_newline(b, terminator)).collect();
+    String::from_utf8(swapped).expect("swapping ASCII bytes keeps UTF-8 valid")
+}
+
+/// `byte`, or a newline for `terminator` and `terminator` for a newline.
+pub fn swap_newline(byte: u8, terminator: u8) -> u8 {
+    match byte {
+        b'\n' => terminator,
+        b if b == terminator => b'\n',
+        b => b,
+    }
+}
+
+/// Like `read_input`, but without requiring the contents to be valid UTF-8.
+pub fn read_input_bytes(filename: &str, budget: Option<Duration>) -> io::Result<Vec<u8>> {
+    open_source(Path::new(filename)).read_bytes(budget)
+}
+
+/// Decodes `bytes` with the first of `encodings`, given by label such as
+/// `latin1` or `utf-16le`, that decodes them without errors. Falls back to
+/// UTF-8 with invalid sequences replaced when none does. Unknown labels are
+/// skipped.
+#[cfg(feature = "encoding")]
+pub fn decode(bytes: &[u8], encodings: &[String]) -> String {
+    encodings.iter()
+             .filter_map(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+             .find_map(|encoding| encoding.decode_without_bom_handling_and_without_replacement(bytes))
+             .map(|text| text.into_owned())
+             .unwrap_or_else(|| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Reads a file without requiring its contents to be valid UTF-8.
+pub fn read_file_bytes(path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    open_decompressed(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Opens `path` for reading, decompressing it on the fly if its extension is
+/// `.bz2`, with the `bzip2` feature, or `.xz`, with the `xz` feature. Other
+/// files are read as they are.
+pub fn open_decompressed(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        #[cfg(feature = "bzip2")]
+        Some("bz2") => Ok(Box::new(bzip2::read::BzDecoder::new(file))),
+        #[cfg(feature = "xz")]
+        Some("xz") => Ok(Box::new(xz2::read::XzDecoder::new(file))),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+/// Whether `path` is compressed in a format `open_decompressed` can
+/// decompress with the features enabled, judged by its extension.
+pub fn is_compressed(path: &Path) -> bool {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    (extension == Some("bz2") && cfg!(feature = "bzip2")) || (extension == Some("xz") && cfg!(feature = "xz"))
+}
+
+/// Whether `filename` has glob wildcards in it: `*`, `?` or `[`.
+pub fn is_glob(filename: &str) -> bool {
+    filename.contains(['*', '?', '['])
+}
+
+/// Expands a shell-style glob into the existing paths that match it, sorted.
+/// Wildcards may appear in any component of the path: `*` matches any run of
+/// characters, `?` any one character and `[abc]`, `[a-z]` or `[!abc]` one
+/// character from a set. As in the shell, they don't match a leading `.`.
+pub fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut paths = vec![if pattern.starts_with('/') { PathBuf::from("/") } else { PathBuf::new() }];
+
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        if !is_glob(component) {
+            for path in &mut paths {
+                path.push(component);
+            }
+            continue;
+        }
+
+        let pattern: Vec<char> = component.chars().collect();
+        paths = paths.into_iter()
+                     .flat_map(|dir| {
+                         let entries = fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { &dir });
+                         let mut names: Vec<String> = entries.into_iter()
+                                                             .flatten()
+                                                             .flatten()
+                                                             .filter_map(|entry| entry.file_name().into_string().ok())
+                                                             .filter(|name| !name.starts_with('.') || pattern[0] == '.')
+                                                             .filter(|name| glob_match(&pattern, &name.chars().collect::<Vec<_>>()))
+                                                             .collect();
+                         names.sort();
+                         names.into_iter().map(move |name| dir.join(name))
+                     })
+                     .collect();
+    }
+
+    paths.retain(|path| path.exists());
+    paths
+}
+
+/// Whether `name` matches the glob `pattern`, both as characters.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| glob_match(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && glob_match(&pattern[1..], &name[1..]),
+        Some('[') => match class_match(&pattern[1..], name.first().copied()) {
+            Some((matched, rest)) => matched && glob_match(rest, &name[1..]),
+            // An unclosed `[` is just a character.
+            None => name.first() == Some(&'[') && glob_match(&pattern[1..], &name[1..]),
+        },
+        Some(c) => name.first() == Some(c) && glob_match(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Matches `c` against the set at the start of `class`, just after its `[`.
+/// Returns whether it matched and the pattern after the closing `]`, or
+/// `None` if there's no closing `]`.
+fn class_match(class: &[char], c: Option<char>) -> Option<(bool, &[char])> {
+    let (negated, class) = match class.first() {
+        Some('!') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    // A `]` first in the set is part of it rather than its end.
+    let end = class.iter().skip(1).position(|&c| c == ']')? + 1;
+    let set = &class[..end];
+
+    let c = match c {
+        Some(c) => c,
+        None => return Some((false, &class[end + 1..])),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            found |= (set[i]..=set[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated, &class[end + 1..]))
+}
+
+/// Walks `root` and returns every file under it that doesn't look binary,
+/// sorted by path. A plain file as `root` is returned as is. Hidden files and
+/// directories, those whose names start with `.`, are skipped unless
+/// `hidden` is set; `root` itself is always searched.
+pub fn find_text_files(root: &Path, hidden: bool) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = walk_text_files(root, hidden)?.collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Lazily walks `root`, yielding regular files that don't look binary as
+/// they're discovered; FIFOs, sockets and devices are skipped, so reading
+/// one can't block the search. Only the entries of the directories on the current path are
+/// held in memory, not the whole tree. Entries are visited in sorted order,
+/// so files come out sorted by path.
+///
+/// An unreadable `root` is an error; unreadable directories below it are
+/// skipped, as are hidden entries unless `hidden` is set.
+pub fn walk_text_files(root: &Path, hidden: bool) -> io::Result<TextFiles> {
+    let entries = match open_source(root) {
+        Source::Dir(dir) => sorted_entries(&dir)?,
+        _ => vec![root.to_path_buf()],
+    };
+
+    Ok(TextFiles { worklist: vec![entries.into_iter()], root: root.to_path_buf(), hidden, binary: None })
+}
+
+pub struct TextFiles {
+    worklist: Vec<vec::IntoIter<PathBuf>>,
+    root: PathBuf,
+    hidden: bool,
+    binary: Option<Arc<Mutex<Vec<PathBuf>>>>,
+}
+
+impl TextFiles {
+    /// Also records in `binary` each file skipped for looking binary.
+    pub fn recording_binary(self, binary: Arc<Mutex<Vec<PathBuf>>>) -> TextFiles {
+        TextFiles { binary: Some(binary), ..self }
+    }
+}
+
+impl Iterator for TextFiles {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            let path = match self.worklist.last_mut()?.next() {
+                Some(path) => path,
+                None => {
+                    self.worklist.pop();
+                    continue;
+                }
+            };
+
+            if !self.hidden && path != self.root && is_hidden(&path) {
+                continue;
+            }
+
+            if path.is_dir() {
+                if let Ok(entries) = sorted_entries(&path) {
+                    self.worklist.push(entries.into_iter());
+                }
+            } else if path.is_file() {
+                match is_binary(&path) {
+                    Ok(false) => return Some(path),
+                    Ok(true) => {
+                        if let Some(binary) = &self.binary {
+                            binary.lock().unwrap().push(path);
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// File types by extension, or by whole file name for files like `Makefile`
+/// that have none.
+const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["c", "h"]),
+    ("cpp", &["cc", "cpp", "cxx", "hh", "hpp"]),
+    ("css", &["css"]),
+    ("go", &["go"]),
+    ("html", &["htm", "html"]),
+    ("java", &["java"]),
+    ("js", &["js", "mjs", "cjs"]),
+    ("json", &["json"]),
+    ("make", &["mk", "Makefile"]),
+    ("markdown", &["md", "markdown"]),
+    ("python", &["py", "pyi"]),
+    ("ruby", &["rb"]),
+    ("rust", &["rs"]),
+    ("sh", &["sh", "bash", "zsh"]),
+    ("toml", &["toml"]),
+    ("ts", &["ts", "tsx"]),
+    ("txt", &["txt"]),
+    ("yaml", &["yaml", "yml"]),
+];
+
+/// The type of the file at `path`, judged by its extension, or `unknown`.
+pub fn file_type(path: &Path) -> &'static str {
+    let key = path.extension().or_else(|| path.file_name()).and_then(|key| key.to_str());
+    FILE_TYPES.iter()
+              .find(|(_, keys)| key.is_some_and(|key| keys.contains(&key)))
+              .map_or("unknown", |&(name, _)| name)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Whether `path` looks binary, judged by its contents after decompression.
+fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut head = Vec::new();
+    open_decompressed(path)?.take(SNIFF_LEN).read_to_end(&mut head)?;
+    Ok(head.contains(&0))
+}
@@ -0,0 +1,45 @@
+use std::env;
+use std::io;
+use std::path::Path;
+
+use git2::{Error, Repository};
+
+/// Reads `filename` as it was at the revision `rev` of the repository
+/// containing it. `filename` is relative to the current directory as usual,
+/// and needn't still exist in the working tree.
+pub fn read_at_rev(rev: &str, filename: &str) -> io::Result<String> {
+    let path = env::current_dir()?.join(filename);
+    // The file may be gone from the working tree, so look for the repository
+    // from the nearest directory that's still there.
+    let dir = path.ancestors()
+                  .skip(1)
+                  .find(|dir| dir.is_dir())
+                  .ok_or_else(|| invalid(format!("{} isn't in a git repository", filename)))?;
+    let repo = Repository::discover(dir)
+        .map_err(|err| invalid(format!("{} isn't in a git repository: {}", filename, err.message())))?;
+    let workdir = repo.workdir()
+                      .ok_or_else(|| invalid(format!("{} is in a bare repository", filename)))?
+                      .canonicalize()?;
+    let rest = path.strip_prefix(dir).unwrap_or(Path::new(""));
+    let in_repo = dir.canonicalize()?
+                     .strip_prefix(&workdir)
+                     .map_err(|_| invalid(format!("{} isn't in the repository's working tree", filename)))?
+                     .join(rest);
+
+    let tree = repo.revparse_single(rev)
+                   .and_then(|object| object.peel_to_tree())
+                   .map_err(|err| invalid(format!("Unknown revision {}: {}", rev, err.message())))?;
+    let entry = tree.get_path(&in_repo).map_err(|_| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist at {}", filename, rev))
+    })?;
+    let blob = entry.to_object(&repo)
+                    .and_then(|object| object.peel_to_blob())
+                    .map_err(|_: Error| invalid(format!("{} isn't a file at {}", filename, rev)))?;
+
+    String::from_utf8(blob.content().to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -1,101 +1,5992 @@
-use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut f = File::open(config.filename)?;
+#[cfg(feature = "cache")]
+mod cache;
+mod error;
+pub mod file;
+mod matcher;
+pub mod output;
+#[cfg(feature = "config-file")]
+mod rc;
+#[cfg(feature = "git")]
+mod git;
+#[cfg(feature = "script")]
+mod script;
 
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
+pub use error::{ConfigError, Error, ErrorFormat};
+pub use matcher::{field_of, tag_lines, Matcher, RegexCache};
 
-    let results = if config.case_sensitive {
-        search(&config.query, &contents)
+/// Upper bound on file contents loaded by reader threads but not yet searched.
+const PIPELINE_DEPTH: usize = 64;
+
+/// How many of the slowest files `--timing` lists.
+const TIMING_FILES: usize = 10;
+
+/// How far `--sentence-context` looks for the end of a sentence before
+/// giving up and stopping at the end of the line.
+const SENTENCE_SCAN: usize = 1000;
+
+/// How many ranges of lines `--histogram` splits a file into.
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// Where `--hyperlink` points paths unless `--hyperlink-format` says
+/// otherwise.
+const HYPERLINK_FORMAT: &str = "file://{path}#L{line}";
+
+/// Runs the search described by `config`, printing results to stdout or
+/// `--output-file`. Returns how many matches were printed, which the REPL
+/// and `--replace-interactive` leave at 0.
+pub fn run(mut config: Config) -> Result<usize, Error> {
+    let is_terminal = config.output_file.is_none() && io::stdout().is_terminal();
+    config.color = config.color.resolve(is_terminal);
+    config.wrap = config.wrap.resolve(is_terminal);
+
+    if let Some(path) = config.output_file.clone() {
+        if !config.force && overwrites_input(&config, &path) {
+            return Err(Error::Config(ConfigError::ConflictingOptions(format!(
+                "--output-file {} is one of the files searched; add --force to overwrite it",
+                path.display()
+            ))));
+        }
+
+        // Whatever was found is saved even if the run was cut short, as it
+        // would be printed.
+        let mut results = Vec::new();
+        let result = run_with_writer(config, &mut results);
+        file::write_file(&path, &results)?;
+        return result;
+    }
+
+    // Locking once and buffering avoids a lock and a write call per line,
+    // which dominates the run time on large result sets.
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    if config.repl {
+        repl(config, io::stdin().lock(), &mut out)?;
+        return Ok(0);
+    }
+
+    if config.replace_interactive {
+        let replaced = replace_interactive(&config, io::stdin().lock(), &mut out)?;
+        writeln!(out, "{} replacements", replaced)?;
+        out.flush()?;
+        return Ok(0);
+    }
+
+    // Flush even if the run was cut short, so partial results aren't lost.
+    let result = run_with_writer(config, &mut out);
+    out.flush()?;
+
+    result
+}
+
+/// Whether writing `--output-file` to `path` would replace a file the search
+/// reads: one named, or with `-r` any file under a directory named.
+fn overwrites_input(config: &Config, path: &Path) -> bool {
+    config.filenames.iter().map(Path::new).any(|input| {
+        let in_tree = || match (path.canonicalize(), input.canonicalize()) {
+            (Ok(path), Ok(root)) => path.starts_with(root),
+            _ => false,
+        };
+        file::same_file(input, path) || (config.recursive && input.is_dir() && in_tree())
+    })
+}
+
+/// Runs the search described by `config`, writing results to `out`, and
+/// returns how many matches were written, for `--fail-if-matches` and
+/// `--fail-if-no-matches`. `ColorChoice::Auto` is treated as `Never` here;
+/// `run` resolves it against the real terminal first.
+pub fn run_with_writer<W: Write>(config: Config, out: &mut W) -> Result<usize, Error> {
+    let deadline = config.max_runtime.map(|limit| Deadline::start(limit, &config.cancel));
+    let on_timeout = config.on_timeout;
+
+    // Input records are read with their terminator swapped for a newline, so
+    // swapping back on the way out terminates the output records the same way.
+    let mut found = 0;
+    let result = if config.line_terminator != b'\n' {
+        let terminator = config.line_terminator;
+        write_results(config, &mut output::SwapNewlines::new(out, terminator), &mut found)
     } else {
-        search_case_insensitive(&config.query, &contents)
+        write_results(config, out, &mut found)
+    };
+
+    match result {
+        Ok(()) => Ok(found),
+        Err(Error::Cancelled { files_searched }) if deadline.is_some_and(|d| d.expired()) => match on_timeout {
+            OnTimeout::Partial => Ok(found),
+            OnTimeout::Error => Err(Error::TimedOut { files_searched }),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `matches` matching lines, as counted by `run`, should fail the run
+/// under `--fail-if-matches` or `--fail-if-no-matches`.
+pub fn fails_threshold(fail_if_matches: Option<usize>, fail_if_no_matches: bool, matches: usize) -> bool {
+    fail_if_matches.is_some_and(|n| matches >= n) || (fail_if_no_matches && matches == 0)
+}
+
+/// A `--max-runtime` timer. When it runs out it sets the cancel flag, so the
+/// search stops between files just as on Ctrl-C; dropping it first stops the
+/// timer without touching the flag.
+struct Deadline {
+    expired: Arc<AtomicBool>,
+    _stop: mpsc::Sender<()>,
+}
+
+impl Deadline {
+    fn start(limit: Duration, cancel: &Arc<AtomicBool>) -> Deadline {
+        let expired = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let expire = {
+            let (expired, cancel) = (Arc::clone(&expired), Arc::clone(cancel));
+            move || {
+                expired.store(true, Ordering::SeqCst);
+                cancel.store(true, Ordering::SeqCst);
+            }
+        };
+
+        // With no time at all, nothing is searched, rather than whatever
+        // happens to be reached before the timer thread gets going.
+        if limit.is_zero() {
+            expire();
+        } else {
+            thread::spawn(move || {
+                if stopped.recv_timeout(limit) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    expire();
+                }
+            });
+        }
+        Deadline { expired, _stop: stop }
+    }
+
+    /// Whether the time ran out, as opposed to the search being cancelled
+    /// some other way.
+    fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
+
+/// `run_with_writer`, once the output is set up. `found` counts the matches
+/// as they're written, so a run cut short still has the count of what it
+/// printed.
+fn write_results<W: Write>(config: Config, out: &mut W, found: &mut usize) -> Result<(), Error> {
+    let matcher = Matcher::new(&config)?;
+
+    if config.count {
+        return write_counts(out, &config, &matcher, found);
+    }
+
+    if config.count_unique {
+        return write_unique_counts(out, &config, &matcher, found);
+    }
+
+    if config.count_replacements {
+        return write_replacement_counts(out, &config, &matcher, found);
+    }
+
+    if config.longest {
+        return write_longest(out, &config, &matcher, found);
+    }
+
+    if config.only_count_files {
+        let matched = if config.recursive {
+            count_matching_files(walk_roots(&config)?, &matcher, &config.cancel)?
+        } else {
+            let mut matched = 0;
+            for filename in &config.filenames {
+                let contents = read_input(&config, filename)?;
+                if !first_match(Path::new(filename), &contents, &matcher).is_empty() {
+                    matched += 1;
+                }
+            }
+            matched
+        };
+
+        // Each file counted has at least one match; more isn't looked for.
+        *found += matched;
+        writeln!(out, "{} files matched", matched)?;
+        return Ok(());
+    }
+
+    if config.line_numbers_only {
+        return write_line_numbers(out, &config, &matcher, found);
+    }
+
+    if config.json {
+        return write_json(out, &config, &matcher, found);
+    }
+
+    if config.summary_line {
+        return write_summary_line(out, &config, &matcher, found);
+    }
+
+    if let Some(n) = config.nth {
+        return write_nth(out, &config, &matcher, n, found);
+    }
+
+    let timings = Timings::new(config.timing, config.stats);
+
+    if config.recursive {
+        let paths = walk_roots(&config)?;
+
+        let mut printed = false;
+        // Each file printed, with how many replacements `--replace-count`
+        // found in it.
+        let mut replaced = Vec::new();
+        let mut tally = |group: &[FileMatch]| {
+            if config.replace_count && group.iter().filter(|m| m.is_match).count() >= config.min_matches {
+                replaced.push((shown_path(&config, &group[0].path).display().to_string(), replacements_in(&config, &matcher, group)));
+            }
+        };
+        if config.sort_files || config.sort_by_relevance || config.reverse || config.print_empty {
+            // Every file walked, for `--print-empty` to find those that
+            // didn't match.
+            let mut walked = Vec::new();
+            let paths = paths.inspect(|path| {
+                if config.print_empty {
+                    walked.push(path.clone());
+                }
+            });
+            let mut results = Vec::new();
+            let searched = stream_tree(&config, &matcher, &timings, OnUnreadable::from_config(&config), paths, |group| {
+                results.extend(group);
+                Ok(())
+            });
+
+            // Print whatever was found even if the search was interrupted.
+            results.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+            let mut groups: Vec<&[FileMatch]> = results.chunk_by(|a, b| a.path == b.path).collect();
+            if config.sort_by_relevance {
+                // Ties go by path, so the order doesn't depend on which
+                // searcher thread finished first.
+                groups.sort_by_cached_key(|group| {
+                    let matches = group.iter().filter(|m| m.is_match);
+                    let relevance = relevance(matches.map(|m| (m.line.as_str(), matcher.find_spans(&m.line))));
+                    (Reverse(relevance), &group[0].path)
+                });
+            }
+            if config.reverse {
+                groups.reverse();
+            }
+
+            let mut entries: Vec<(&Path, Option<&[FileMatch]>)> = groups.into_iter()
+                                                                       .map(|group| (group[0].path.as_path(), Some(group)))
+                                                                       .collect();
+            if config.print_empty {
+                let matched: HashSet<&Path> = entries.iter().map(|&(path, _)| path).collect();
+                entries.extend(walked.iter().filter(|path| !matched.contains(path.as_path())).map(|path| (path.as_path(), None)));
+                entries.sort_by_key(|&(path, _)| path);
+                if config.reverse {
+                    entries.reverse();
+                }
+            }
+            for (path, group) in entries {
+                match group {
+                    Some(group) => {
+                        tally(group);
+                        write_file_group(out, &config, &matcher, group, &mut printed, found)?;
+                    }
+                    None => write_no_matches(out, &config, &shown_path(&config, path).display().to_string(), path, &mut printed)?,
+                }
+            }
+            searched?;
+        } else {
+            stream_tree(&config, &matcher, &timings, OnUnreadable::from_config(&config), paths, |group| {
+                tally(&group);
+                write_file_group(out, &config, &matcher, &group, &mut printed, found)
+            })?;
+        }
+
+        if config.replace_count {
+            replaced.sort();
+            write_replacement_summary(out, printed, &replaced)?;
+        }
+        timings.write(out)?;
+        return Ok(());
+    }
+
+    if config.byte_mode {
+        return write_bytes_results(out, &config, &matcher, found);
+    }
+
+    if let [filename] = config.filenames.as_slice() {
+        let contents = read_input(&config, filename)?;
+        timings.time(Path::new(filename), &contents, || write_file_results(out, &config, &matcher, &contents, found))?;
+        if config.replace_count {
+            writeln!(out, "{} replacements", count_replacements(&config, &matcher, &contents))?;
+        }
+        timings.write(out)?;
+        return Ok(());
+    }
+
+    // With several files, each one's results go under a heading, and files
+    // without matches are left out.
+    let mut printed = false;
+    let mut replaced = Vec::new();
+    for (searched, filename) in config.filenames.iter().enumerate() {
+        if config.cancel.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { files_searched: searched });
+        }
+
+        let contents = read_input(&config, filename)?;
+        let mut results = Vec::new();
+        timings.time(Path::new(filename), &contents, || write_file_results(&mut results, &config, &matcher, &contents, &mut *found))?;
+        if config.replace_count {
+            replaced.push((filename.clone(), count_replacements(&config, &matcher, &contents)));
+        }
+
+        if !results.is_empty() {
+            if printed {
+                writeln!(out)?;
+            }
+            writeln!(out, "File: {}", linked(&config, filename, Path::new(filename), None))?;
+            out.write_all(&results)?;
+            printed = true;
+        } else if config.print_empty {
+            write_no_matches(out, &config, filename, Path::new(filename), &mut printed)?;
+        }
+    }
+
+    if config.replace_count {
+        write_replacement_summary(out, printed, &replaced)?;
+    }
+
+    timings.write(out)?;
+    Ok(())
+}
+
+/// Prints only the `n`th matching line of the run, with its context, for
+/// `--nth`. Files are searched one at a time in the order they're named or,
+/// with `-r`, found, so the count doesn't depend on thread scheduling, and
+/// the search stops once the line is found.
+fn write_nth<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, n: usize, found: &mut usize) -> Result<(), Error> {
+    let (before, after) = config.context();
+    let headed = config.recursive || config.filenames.len() > 1;
+    let mut remaining = n;
+
+    // Whether `contents` held the line, which is then printed.
+    let mut print_nth = |heading: &str, path: &Path, contents: &str| -> Result<bool, Error> {
+        let lines = search_context(matcher, contents, before, after);
+        let target = match lines.iter().filter(|line| line.is_match).nth(remaining - 1) {
+            Some(line) => line.line_num,
+            None => {
+                remaining -= lines.iter().filter(|line| line.is_match).count();
+                return Ok(false);
+            }
+        };
+
+        if headed {
+            writeln!(out, "File: {}", linked(config, heading, path, None))?;
+        }
+        let window = target.saturating_sub(before)..=target + after;
+        let lines: Vec<ContextLine> = lines.into_iter().filter(|line| window.contains(&line.line_num)).collect();
+        if before > 0 || after > 0 || config.show_context_markers {
+            write_context_lines(out, config, &lines, config.color == ColorChoice::Always)?;
+        } else {
+            writeln!(out, "{}", paint(config, matcher, lines[0].line))?;
+        }
+        *found += 1;
+        Ok(true)
     };
 
-    for line in results {
-        println!("{}", line);
+    if config.recursive {
+        for (searched, path) in walk_roots(config)?.enumerate() {
+            if config.cancel.load(Ordering::SeqCst) {
+                return Err(Error::Cancelled { files_searched: searched });
+            }
+            let contents = match file::read_file(&path) {
+                Ok(contents) => contents,
+                Err(err) if config.fail_fast => {
+                    return Err(Error::Io(io::Error::new(err.kind(), format!("{}: {}", path.display(), err))));
+                }
+                Err(_) => continue,
+            };
+            if print_nth(&shown_path(config, &path).display().to_string(), &path, &contents)? {
+                break;
+            }
+        }
+        return Ok(());
     }
 
+    for filename in &config.filenames {
+        let contents = read_input(config, filename)?;
+        if print_nth(filename, Path::new(filename), &contents)? {
+            break;
+        }
+    }
     Ok(())
 }
 
-pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str>{
-    contents.lines()
-            .filter(|line| line.contains(query))
-            .collect()
+/// Time spent searching each file, kept for `--timing`, and the totals
+/// reported by `--stats`. Only the totals are kept when timing is off.
+struct Timings {
+    enabled: bool,
+    files: Mutex<Vec<(PathBuf, Duration)>>,
+    stats: bool,
+    started: Instant,
+    searched: AtomicUsize,
+    bytes: AtomicUsize,
 }
 
-pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str>{
-    let query = query.to_lowercase();
-    let mut results = Vec::new();
+impl Timings {
+    fn new(enabled: bool, stats: bool) -> Timings {
+        Timings {
+            enabled,
+            files: Mutex::new(Vec::new()),
+            stats,
+            started: Instant::now(),
+            searched: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
 
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query) {
-            results.push(line)
+    /// Runs `search` over `contents`, recording how long it took against
+    /// `path`.
+    fn time<T, F: FnOnce() -> T>(&self, path: &Path, contents: &str, search: F) -> T {
+        self.searched.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(contents.len(), Ordering::SeqCst);
+        if !self.enabled {
+            return search();
         }
+
+        let start = Instant::now();
+        let result = search();
+        self.files.lock().unwrap().push((path.to_path_buf(), start.elapsed()));
+        result
     }
 
-    results
+    /// Prints the slowest files, slowest first, and then the totals, each
+    /// after a blank line when they're kept.
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.enabled {
+            let mut files = self.files.lock().unwrap();
+            files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            writeln!(out)?;
+            writeln!(out, "Slowest files:")?;
+            for (path, elapsed) in files.iter().take(TIMING_FILES) {
+                writeln!(out, "{:.3}ms {}", elapsed.as_secs_f64() * 1000.0, path.display())?;
+            }
+        }
+
+        if self.stats {
+            let elapsed = self.started.elapsed();
+            let bytes = self.bytes.load(Ordering::SeqCst);
+            writeln!(out)?;
+            writeln!(out, "Files searched: {}", self.searched.load(Ordering::SeqCst))?;
+            writeln!(out, "Bytes read: {}", bytes)?;
+            writeln!(out, "Elapsed: {:.3}ms", elapsed.as_secs_f64() * 1000.0)?;
+            // A search too quick to measure has no meaningful rate.
+            match throughput(bytes, elapsed) {
+                Some(rate) => writeln!(out, "Throughput: {:.2} MB/s", rate)?,
+                None => writeln!(out, "Throughput: n/a")?,
+            }
+        }
+        Ok(())
+    }
 }
 
-pub struct Config {
-    pub query: String,
-    pub filename: String,
-    pub case_sensitive: bool
+/// Whether anything in the files `config` names matches, for `--quiet`.
+/// Stops at the first match found, whether in the explicit files or anywhere
+/// in a recursive search. `--max-runtime` stops it as it does `run`.
+pub fn any_match(config: &Config) -> Result<bool, Error> {
+    let deadline = config.max_runtime.map(|limit| Deadline::start(limit, &config.cancel));
+
+    match find_any_match(config) {
+        Err(Error::Cancelled { files_searched }) if deadline.is_some_and(|d| d.expired()) => match config.on_timeout {
+            OnTimeout::Partial => Ok(false),
+            OnTimeout::Error => Err(Error::TimedOut { files_searched }),
+        },
+        result => result,
+    }
 }
 
-impl Config {
-    pub fn new(mut args: std::env::Args) -> Result<Config, &'static str> {
-        args.next();
+/// `any_match`, once the deadline is set.
+fn find_any_match(config: &Config) -> Result<bool, Error> {
+    let matcher = Matcher::new(config)?;
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
+    if config.recursive {
+        let (found, _) = any_file_matches(walk_roots(config)?, &matcher, config.min_matches, &config.cancel)?;
+        return Ok(found);
+    }
+
+    for (searched, filename) in config.filenames.iter().enumerate() {
+        if config.cancel.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled { files_searched: searched });
+        }
+        let found = if config.byte_mode {
+            !search_bytes(&matcher, &file::read_input_bytes(filename, config.max_runtime)?).is_empty()
+        } else {
+            !first_match(Path::new(filename), &read_input(config, filename)?, &matcher).is_empty()
         };
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file name"),
+        if found {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Goes through every occurrence of the query in the files, showing the line
+/// before and after replacing it and asking on `input` whether to: `y` replace
+/// it, `n` leave it, `a` replace it and all the rest without asking, or `q`
+/// stop asking and leave the rest. Files are rewritten in place, only if at
+/// least one of their replacements was accepted. Returns how many
+/// replacements were made.
+pub fn replace_interactive<R: BufRead, W: Write>(config: &Config, input: R, out: &mut W) -> Result<usize, Error> {
+    let matcher = Matcher::new(config)?;
+    let replacement = config.replace.as_deref().unwrap_or_default();
+    let mut answers = input.lines();
+    let mut replace_all = false;
+    let mut quit = false;
+    let mut total = 0;
+
+    for filename in &config.filenames {
+        // Read as `read_input` would, but keep any byte-order mark to write
+        // back with the edits.
+        let mut contents = file::read_input(filename, config.max_runtime)?;
+        let bom = if !config.no_strip_bom && contents.starts_with('\u{feff}') { "\u{feff}" } else { "" };
+        contents.drain(..bom.len());
+        let matcher = matcher.for_contents(&contents);
+        let mut edited = String::from(bom);
+        let mut accepted = 0;
+
+        for (i, raw) in contents.split_inclusive('\n').enumerate() {
+            let line = raw.trim_end_matches(['\n', '\r']);
+            let ending = &raw[line.len()..];
+            if quit || !matcher.is_match_at(i + 1, line) {
+                edited.push_str(raw);
+                continue;
+            }
+
+            let mut done = String::new();
+            let mut last = 0;
+            for span in matcher.find_spans(line) {
+                let (replaced, _) = matcher.replace(&line[span.clone()], replacement);
+                let yes = replace_all || {
+                    writeln!(out, "{}:{}", filename, config.shown_number(i + 1))?;
+                    writeln!(out, "- {}{}", done, &line[last..])?;
+                    writeln!(out, "+ {}{}{}{}", done, &line[last..span.start], replaced, &line[span.end..])?;
+                    write!(out, "Replace? [y/n/a/q] ")?;
+                    out.flush()?;
+
+                    match answers.next().transpose()?.as_deref().map(str::trim) {
+                        Some("y") => true,
+                        Some("a") => {
+                            replace_all = true;
+                            true
+                        }
+                        Some("q") | None => {
+                            quit = true;
+                            false
+                        }
+                        _ => false,
+                    }
+                };
+                if quit {
+                    break;
+                }
+
+                done.push_str(&line[last..span.start]);
+                if yes {
+                    done.push_str(&replaced);
+                    accepted += 1;
+                } else {
+                    done.push_str(&line[span.clone()]);
+                }
+                last = span.end;
+            }
+
+            edited.push_str(&done);
+            edited.push_str(&line[last..]);
+            edited.push_str(ending);
+        }
+
+        if accepted > 0 {
+            fs::write(filename, edited)?;
+            total += accepted;
+        }
+    }
+
+    Ok(total)
+}
+
+/// Loads the file once, then reads queries from `input` one per line and
+/// prints each one's matches to `out`, as a single-file run would. A blank
+/// line, `:quit` or the end of `input` stops the loop. A query that doesn't
+/// compile is reported and the loop carries on.
+pub fn repl<R: BufRead, W: Write>(mut config: Config, input: R, out: &mut W) -> Result<(), Error> {
+    let contents = read_input(&config, &config.filenames[0])?;
+    let mut cache = RegexCache::new();
+    let mut lines = input.lines();
+
+    loop {
+        write!(out, "> ")?;
+        out.flush()?;
+
+        let query = match lines.next() {
+            Some(line) => line?,
+            None => break,
         };
+        let query = query.trim_end();
+        if query.is_empty() || query == ":quit" {
+            break;
+        }
+
+        config.query = query.to_string();
+        match Matcher::with_cache(&config, &mut cache) {
+            Ok(matcher) => write_file_results(out, &config, &matcher, &contents, &mut 0)?,
+            Err(err) => writeln!(out, "Invalid query: {}", err)?,
+        }
+    }
 
-        let case_sensitive = env::var("CASE_INSENSITIVE").is_err();
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Lazily walks every root given on the command line, in order.
+fn walk_roots(config: &Config) -> Result<impl Iterator<Item = PathBuf> + Send, Error> {
+    walk_roots_recording(config, None)
+}
 
-        Ok(Config { query, filename, case_sensitive })
+/// `walk_roots`, also recording in `binary` the files skipped for looking
+/// binary.
+fn walk_roots_recording(config: &Config, binary: Option<&Arc<Mutex<Vec<PathBuf>>>>) -> Result<impl Iterator<Item = PathBuf> + Send, Error> {
+    let mut walks = Vec::new();
+    for root in &config.filenames {
+        let walk = file::walk_text_files(Path::new(root), config.hidden)?;
+        walks.push(match binary {
+            Some(binary) => walk.recording_binary(Arc::clone(binary)),
+            None => walk,
+        });
     }
+    // Files that can't be identified are always searched.
+    let dedupe = config.dedupe_files;
+    let mut seen = HashSet::new();
+    Ok(walks.into_iter()
+            .flatten()
+            .filter(move |path| !dedupe || file::file_id(path).is_none_or(|id| seen.insert(id))))
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// Streams each file's results, with context lines when context is set.
+fn stream_tree<I, F>(config: &Config, matcher: &Matcher, timings: &Timings, on_unreadable: OnUnreadable, paths: I, each: F) -> Result<usize, Error>
+    where I: Iterator<Item = PathBuf> + Send,
+          F: FnMut(Vec<FileMatch>) -> Result<(), Error>
+{
+    // Highlighting, `-o` and `--annotate` need the match spans.
+    let details = config.color == ColorChoice::Always || config.only_matching || config.annotate;
+    let (before, after) = config.context();
+    let search = |path: &Path, contents: &str| {
+        timings.time(path, contents, || {
+            if before == 0 && after == 0 {
+                search_file(path, contents, matcher, details)
+            } else {
+                search_file_context(path, contents, matcher, before, after, details)
+            }
+        })
+    };
 
-    #[test]
-    fn case_sensitive() {
-        let query = "duct";
-        let content = "\
-Rust:
-safe, fast, productive.
-Pick three.
-Duct tape.";
-        assert_eq!(
-            vec!["safe, fast, productive."],
-            search(query, content)
-        );
+    #[cfg(feature = "cache")]
+    if let Some(dir) = &config.cache_dir {
+        return stream_cached(config, dir, details, on_unreadable, paths, search, each);
     }
+    pipeline(paths, &config.cancel, on_unreadable, search, each)
+}
 
-    #[test]
-    fn case_insensitive() {
-        let query = "rUsT";
-        let contents = "\
-Rust:
-safe, fast, productive.
-Pick three.
-Trust me.";
-        assert_eq!(
-            vec!["Rust:", "Trust me."],
-            search_case_insensitive(query, contents)
-        );
+/// `stream_tree` with `--cache`: files unchanged since the cache was written
+/// get their recorded results, which come first, and only the rest are read
+/// and searched. The cache is then updated, or left as it was if it can't be
+/// written.
+#[cfg(feature = "cache")]
+fn stream_cached<I, S, F>(config: &Config, dir: &Path, details: bool, on_unreadable: OnUnreadable, paths: I, search: S, mut each: F) -> Result<usize, Error>
+    where I: Iterator<Item = PathBuf> + Send,
+          S: Fn(&Path, &str) -> Vec<FileMatch> + Sync,
+          F: FnMut(Vec<FileMatch>) -> Result<(), Error>
+{
+    let signature = cache::signature(config, details);
+    let cache_file = cache::file_for(dir, &signature);
+    let mut cache = cache::Cache::load(&cache_file, &signature);
+
+    let mut changed = Vec::new();
+    let mut reused = 0;
+    for path in paths {
+        match cache.get(&path) {
+            Some(found) => {
+                reused += 1;
+                if !found.is_empty() {
+                    each(found)?;
+                }
+            }
+            None => changed.push(path),
+        }
+    }
+
+    let fresh = Mutex::new(Vec::new());
+    let searched = pipeline(changed.into_iter(), &config.cancel, on_unreadable, |path, contents| {
+        let found = search(path, contents);
+        fresh.lock().unwrap().push((path.to_path_buf(), found.clone()));
+        found
+    }, each);
+
+    for (path, found) in fresh.into_inner().unwrap() {
+        cache.insert(&path, found);
+    }
+    let _ = cache.save(&cache_file);
+
+    match searched {
+        Ok(files_searched) => Ok(files_searched + reused),
+        Err(Error::Cancelled { files_searched }) => Err(Error::Cancelled { files_searched: files_searched + reused }),
+        Err(err) => Err(err),
+    }
+}
+
+/// Megabytes per second for `bytes` read in `elapsed`, or `None` when no
+/// time passed.
+fn throughput(bytes: usize, elapsed: Duration) -> Option<f64> {
+    let secs = elapsed.as_secs_f64();
+    (secs > 0.0).then(|| bytes as f64 / 1_000_000.0 / secs)
+}
+
+/// Prints `path: (no matches)` for a file `--print-empty` lists, spaced from
+/// the files around it as their results are.
+fn write_no_matches<W: Write>(out: &mut W, config: &Config, shown: &str, path: &Path, printed: &mut bool) -> io::Result<()> {
+    if *printed && !config.compact && !config.flat {
+        writeln!(out)?;
+    }
+    *printed = true;
+    writeln!(out, "{}: (no matches)", linked(config, shown, path, None))
+}
+
+/// Prints one file's recursive results under a `File:` heading, separated from
+/// the previous file by a blank line, or with `--compact` under a bare
+/// `path:` with no blank line. Lines use the context output format, with
+/// `--` between non-adjacent groups; the tracking of adjacent lines starts
+/// afresh for each file.
+fn write_file_group<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, group: &[FileMatch], printed: &mut bool, found: &mut usize) -> Result<(), Error> {
+    if group.iter().filter(|m| m.is_match).count() < config.min_matches {
+        return Ok(());
+    }
+    // `--replace` prints the matching lines replaced, leaving out those
+    // `--delete` empties.
+    let replaced: Vec<FileMatch>;
+    let group = match config.replace.as_deref() {
+        Some(replacement) => {
+            replaced = group.iter()
+                            .map(|m| FileMatch { line: matcher.replace(&m.line, replacement).0, match_spans: Vec::new(), ..m.clone() })
+                            .filter(|m| !(config.delete && !config.keep_empty && m.line.is_empty()))
+                            .collect();
+            if replaced.is_empty() {
+                return Ok(());
+            }
+            replaced.as_slice()
+        }
+        None => group,
+    };
+    *found += group.iter().filter(|m| m.is_match).count();
+    if config.flat {
+        return write_flat_group(out, config, group, printed);
+    }
+    if *printed && !config.compact {
+        writeln!(out)?;
+    }
+    *printed = true;
+    let path = &group[0].path;
+    let shown = linked(config, &shown_path(config, path).display().to_string(), path, None);
+    if config.compact {
+        write!(out, "{}:", shown)?;
+    } else {
+        write!(out, "File: {}", shown)?;
+    }
+    if config.show_type {
+        write!(out, " [{}]", file::file_type(&group[0].path))?;
+    }
+    writeln!(out)?;
+
+    let mut group: Vec<&FileMatch> = group.iter().collect();
+    sort_by_text(&mut group, config.sort_lines, |m| &m.line);
+
+    if config.only_matching {
+        for m in group {
+            write_only_matching(out, config, m.line_num, &m.line, &m.match_spans)?;
+        }
+        return Ok(());
+    }
+
+    if config.snippet {
+        writeln!(out, "{}", output::snippet(group.iter().map(|m| (m.line_num, m.line.as_str()))))?;
+        return Ok(());
+    }
+
+    let color = config.color == ColorChoice::Always;
+    let lines: Vec<ContextLine> = group.iter()
+                                       .map(|m| ContextLine {
+                                           line_num: m.line_num,
+                                           line: &m.line,
+                                           is_match: m.is_match,
+                                           spans: m.match_spans.clone(),
+                                       })
+                                       .collect();
+
+    if config.sort_lines == SortKey::Line {
+        write_context_lines(out, config, &lines, color)?;
+    } else {
+        // Sorted lines aren't in file order, so gaps between them mean nothing.
+        for line in lines {
+            write_record(out, config, |out| {
+                write_numbered(out, config, line.line_num, &paint(config, matcher, line.line))?;
+                write_carets(out, config, &format!("{}:", config.shown_number(line.line_num)), line.line, &line.spans)
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints one file's recursive results for `--flat`: each line as
+/// `path:num:line`, or `path:num~line` for context, with `--` between groups
+/// that aren't adjacent, including those of different files.
+fn write_flat_group<W: Write>(out: &mut W, config: &Config, group: &[FileMatch], printed: &mut bool) -> Result<(), Error> {
+    let path = &group[0].path;
+    let shown = linked(config, &shown_path(config, path).display().to_string(), path, None);
+    let mut group: Vec<&FileMatch> = group.iter().collect();
+    sort_by_text(&mut group, config.sort_lines, |m| &m.line);
+
+    let (before, after) = config.context();
+    let mut prev_line_num = None;
+    for m in group {
+        let adjacent = prev_line_num.is_some_and(|prev| m.line_num == prev + 1);
+        if (before > 0 || after > 0) && *printed && !adjacent && config.sort_lines == SortKey::Line {
+            writeln!(out, "--")?;
+        }
+        prev_line_num = Some(m.line_num);
+        *printed = true;
+
+        let separator = if m.is_match { ':' } else { '~' };
+        let line_num = config.shown_number(m.line_num);
+        let (line, spans) = shown_line(config, &m.line, m.match_spans.clone());
+        if config.color == ColorChoice::Always {
+            writeln!(out, "{}:{}{}{}", shown, line_num, separator, output::highlight(line, &spans))?;
+        } else {
+            writeln!(out, "{}:{}{}{}", shown, line_num, separator, line)?;
+        }
+    }
+    Ok(())
+}
+
+/// `text`, the printed form of `path`, as a clickable OSC 8 hyperlink to the
+/// file with `--hyperlink`, when colors are on. `{line}` in the link's format
+/// is `line`, or 1 where a path is printed for the whole file.
+fn linked(config: &Config, text: &str, path: &Path, line: Option<usize>) -> String {
+    match &config.hyperlink {
+        Some(format) if config.color == ColorChoice::Always && path != Path::new("-") => {
+            let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+            let url = format.replace("{path}", &absolute.display().to_string())
+                            .replace("{line}", &line.unwrap_or(1).to_string());
+            output::hyperlink(text, &url)
+        }
+        _ => text.to_string(),
+    }
+}
+
+/// `path` as `--relative-to` or else `--path-format` asks for it to be
+/// printed. Falls back to the path as walked if it can't be made relative
+/// or absolute.
+fn shown_path(config: &Config, path: &Path) -> PathBuf {
+    if let Some(base) = &config.relative_to {
+        // Compare canonical paths too, so a relative root and an absolute
+        // base (or the other way around) still line up.
+        let canonical = |path: &Path| path.canonicalize().ok();
+        let relative = path.strip_prefix(base).ok().map(Path::to_path_buf).or_else(|| {
+            Some(canonical(path)?.strip_prefix(canonical(base)?).ok()?.to_path_buf())
+        });
+        return relative.filter(|relative| !relative.as_os_str().is_empty())
+                       .unwrap_or_else(|| path.to_path_buf());
+    }
+
+    match config.path_format {
+        PathFormat::AsWalked => path.to_path_buf(),
+        PathFormat::Relative => {
+            config.filenames
+                  .iter()
+                  .find_map(|root| path.strip_prefix(root).ok())
+                  .filter(|relative| !relative.as_os_str().is_empty())
+                  .unwrap_or(path)
+                  .to_path_buf()
+        }
+        PathFormat::Absolute => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+fn paint(config: &Config, matcher: &Matcher, line: &str) -> String {
+    if config.color == ColorChoice::Always {
+        let (line, spans) = shown_line(config, line, matcher.find_spans(line));
+        output::highlight(line, &spans)
+    } else {
+        shown_line(config, line, Vec::new()).0.to_string()
+    }
+}
+
+/// `line` as it's printed, without its leading whitespace with `--trim`,
+/// and `spans` moved to match. Spans entirely within the trimmed whitespace
+/// are dropped.
+fn shown_line<'a>(config: &Config, line: &'a str, spans: Vec<Range<usize>>) -> (&'a str, Vec<Range<usize>>) {
+    if !config.trim {
+        return (line, spans);
+    }
+
+    let trimmed = line.trim_start();
+    let cut = line.len() - trimmed.len();
+    let spans = spans.into_iter()
+                     .map(|span| span.start.saturating_sub(cut)..span.end.saturating_sub(cut))
+                     .filter(|span| !span.is_empty())
+                     .collect();
+    (trimmed, spans)
+}
+
+/// Context lines as they're printed: see `shown_line`, and numbered as
+/// `Config::shown_number` says.
+fn shown_lines<'a, 'b>(config: &Config, lines: &'b [ContextLine<'a>]) -> Cow<'b, [ContextLine<'a>]> {
+    if !config.trim && !config.zero_based {
+        return Cow::Borrowed(lines);
+    }
+
+    Cow::Owned(lines.iter()
+                    .map(|line| {
+                        let (text, spans) = shown_line(config, line.line, line.spans.clone());
+                        ContextLine { line_num: config.shown_number(line.line_num), line: text, spans, ..line.clone() }
+                    })
+                    .collect())
+}
+
+fn write_file_results<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, contents: &str, found: &mut usize) -> Result<(), Error> {
+    if config.paragraph {
+        let paragraphs = search_paragraphs(matcher, contents);
+        let matching = matcher.for_contents(contents);
+        *found += paragraphs.iter()
+                            .flat_map(|paragraph| (paragraph.start..).zip(&paragraph.lines))
+                            .filter(|&(line_num, line)| matching.is_match_at(line_num, line))
+                            .count();
+        for (i, paragraph) in paragraphs.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            writeln!(out, "Line {}:", config.shown_number(paragraph.start))?;
+            for line in &paragraph.lines {
+                writeln!(out, "{}", paint(config, matcher, line))?;
+            }
+        }
+        return Ok(());
+    }
+
+    if config.histogram {
+        let line_nums: Vec<usize> = search_numbered(matcher, contents).iter().map(|&(n, _)| n).collect();
+        *found += line_nums.len();
+        let buckets = histogram(&line_nums, contents.lines().count(), HISTOGRAM_BUCKETS);
+        let buckets: Vec<_> = buckets.into_iter()
+                                     .map(|(range, count)| (config.shown_number(*range.start())..=config.shown_number(*range.end()), count))
+                                     .collect();
+        output::write_histogram(out, &buckets)?;
+        return Ok(());
+    }
+
+    if config.only_matching {
+        let mut results = search_numbered(matcher, contents);
+        *found += results.len();
+        sort_by_text(&mut results, config.sort_lines, |(_, line)| line);
+        for (line_num, line) in results {
+            write_only_matching(out, config, line_num, line, &matcher.find_spans(line))?;
+        }
+        return Ok(());
+    }
 
+    let (before, after) = config.context();
+
+    if config.snippet {
+        let lines = search_context(matcher, contents, before, after);
+        *found += lines.iter().filter(|line| line.is_match).count();
+        if !lines.is_empty() {
+            writeln!(out, "{}", output::snippet(lines.iter().map(|line| (line.line_num, line.line))))?;
+        }
+        return Ok(());
+    }
+
+    if config.sentence_context {
+        let sentences = search_sentences(matcher, contents);
+        // The sentences are cut from the matching lines.
+        *found += search_numbered(matcher, contents).len();
+        for sentence in sentences {
+            // Sentences can span lines; print each on one.
+            let sentence = sentence.split_whitespace().collect::<Vec<_>>().join(" ");
+            writeln!(out, "{}", paint(config, matcher, &sentence))?;
+        }
+        return Ok(());
+    }
+
+    if config.multiline {
+        let lines = search_multiline(matcher, contents, config.multiline_span, before, after);
+        *found += lines.iter().filter(|line| line.is_match).count();
+        write_context_lines(out, config, &lines, config.color == ColorChoice::Always)?;
+        return Ok(());
+    }
+
+    if config.passthru {
+        let lines = search_passthru(matcher, contents);
+        *found += lines.iter().filter(|line| line.is_match).count();
+        write_context_lines(out, config, &lines, config.color == ColorChoice::Always)?;
+        return Ok(());
+    }
+
+    if let Some(size) = config.context_fill {
+        let color = config.color == ColorChoice::Always;
+        let windows = search_context_fill(matcher, contents, size);
+        *found += windows.len();
+        for (i, window) in windows.iter().enumerate() {
+            if i > 0 {
+                writeln!(out, "--")?;
+            }
+            output::write_context_wrapped(out, &shown_lines(config, window), color, config.wrap.width(), false, config.annotate)?;
+            // Only a file shorter than the window leaves it short.
+            for _ in window.len()..size {
+                writeln!(out, "~")?;
+            }
+        }
+        return Ok(());
+    }
+
+    if (before > 0 || after > 0) && config.no_merge_context {
+        let groups = search_context_groups(matcher, contents, before, after);
+        *found += groups.len();
+        let groups: Vec<Vec<ContextLine>> = groups.iter().map(|group| shown_lines(config, group).into_owned()).collect();
+        let color = config.color == ColorChoice::Always;
+        if config.marks_groups() {
+            write_groups(out, config, groups.iter().map(Vec::as_slice), color)?;
+        } else {
+            output::write_context_groups(out, &groups, color, config.wrap.width(), config.squeeze_blank, config.annotate)?;
+        }
+        return Ok(());
+    }
+
+    // Context output marks each line as a match or not, which
+    // `--show-context-markers` asks for even without context.
+    if before > 0 || after > 0 || config.show_context_markers {
+        let lines = search_context(matcher, contents, before, after);
+        *found += lines.iter().filter(|line| line.is_match).count();
+        write_context_lines(out, config, &lines, config.color == ColorChoice::Always)?;
+        return Ok(());
+    }
+
+    let mut results = search_numbered(matcher, contents);
+    sort_by_text(&mut results, config.sort_lines, |(_, line)| line);
+    if config.sort_by_relevance {
+        results.sort_by_cached_key(|&(_, line)| Reverse(relevance([(line, matcher.find_spans(line))])));
+    }
+
+    let mut prev_blank = false;
+    for (line_num, line) in results {
+        let replaced = config.replace.as_deref().map(|replacement| matcher.replace(line, replacement).0);
+        if config.delete && !config.keep_empty && replaced.as_deref() == Some("") {
+            continue;
+        }
+
+        let blank = line.trim().is_empty();
+        if config.squeeze_blank && blank && prev_blank {
+            continue;
+        }
+        prev_blank = blank;
+        *found += 1;
+
+        let spans = if config.annotate { matcher.find_spans(line) } else { Vec::new() };
+        let (text, spans) = shown_line(config, line, spans);
+        let line = match replaced {
+            Some(replaced) => shown_line(config, &replaced, Vec::new()).0.to_string(),
+            None => paint(config, matcher, line),
+        };
+        // Ranges make the position of a line meaningful, so show it.
+        write_record(out, config, |out| {
+            if config.context_header {
+                let shown = config.shown_number(line_num);
+                writeln!(out, "@@ lines {}-{} @@", shown, shown)?;
+            }
+            if config.line_ranges.is_empty() {
+                writeln!(out, "{}", line)?;
+                write_carets(out, config, "", text, &spans)
+            } else {
+                write_numbered(out, config, line_num, &line)?;
+                write_carets(out, config, &format!("{}:", config.shown_number(line_num)), text, &spans)
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Prints context output as `output::write_context_wrapped` does, except
+/// that with `--record-start`, `--record-end` or `--context-header` the
+/// groups are marked by those in place of the `--` separators.
+fn write_context_lines<W: Write>(out: &mut W, config: &Config, lines: &[ContextLine], color: bool) -> io::Result<()> {
+    let lines = shown_lines(config, lines);
+    if !config.marks_groups() {
+        return output::write_context_wrapped(out, &lines, color, config.wrap.width(), config.squeeze_blank, config.annotate);
+    }
+    write_groups(out, config, output::split_groups(&lines).into_iter(), color)
+}
+
+/// Prints each context group as a record, headed by its line range with
+/// `--context-header`.
+fn write_groups<'a, 'b, W, I>(out: &mut W, config: &Config, groups: I, color: bool) -> io::Result<()>
+    where W: Write,
+          I: Iterator<Item = &'b [ContextLine<'a>]>,
+          'a: 'b
+{
+    for group in groups {
+        write_record(out, config, |out| {
+            if let (true, Some(first), Some(last)) = (config.context_header, group.first(), group.last()) {
+                writeln!(out, "@@ lines {}-{} @@", first.line_num, last.line_num)?;
+            }
+            output::write_context_wrapped(out, group, color, config.wrap.width(), config.squeeze_blank, config.annotate)
+        })?;
+    }
+    Ok(())
+}
+
+/// Runs `write` between the `--record-start` and `--record-end` lines, if
+/// they're set.
+fn write_record<W: Write, F: FnOnce(&mut W) -> io::Result<()>>(out: &mut W, config: &Config, write: F) -> io::Result<()> {
+    if let Some(start) = &config.record_start {
+        writeln!(out, "{}", start)?;
+    }
+    write(out)?;
+    if let Some(end) = &config.record_end {
+        writeln!(out, "{}", end)?;
+    }
+    Ok(())
+}
+
+/// Reads `filename` as `file::read_input` does, or from the `--git-rev`
+/// revision when one is given, or decoded with `--try-encodings`. A leading
+/// byte-order mark is dropped unless `--no-strip-bom` is set.
+fn read_input(config: &Config, filename: &str) -> io::Result<String> {
+    let mut contents = match &config.git_rev {
+        #[cfg(feature = "git")]
+        Some(rev) => git::read_at_rev(rev, filename)?,
+        #[cfg(feature = "encoding")]
+        None if !config.try_encodings.is_empty() => {
+            file::decode(&file::read_input_bytes(filename, config.max_runtime)?, &config.try_encodings)
+        }
+        _ => file::read_input(filename, config.max_runtime)?,
+    };
+    if !config.no_strip_bom {
+        file::strip_bom(&mut contents);
+    }
+    if config.line_terminator != b'\n' {
+        contents = file::swap_newlines(&contents, config.line_terminator);
+    }
+    Ok(contents)
+}
+
+/// Prints each of the matches at `spans` in `line` on its own row, as
+/// `num:match`, or `num:column:match` with `--column`. Columns are 1-based
+/// byte offsets, or 0-based with `--zero-based`.
+fn write_only_matching<W: Write>(out: &mut W, config: &Config, line_num: usize, line: &str, spans: &[Range<usize>]) -> io::Result<()> {
+    let line_num = config.shown_number(line_num);
+    let shown = config.max_matches_per_line.unwrap_or(usize::MAX).min(spans.len());
+    for span in &spans[..shown] {
+        write!(out, "{}:", line_num)?;
+        if config.column {
+            write!(out, "{}:", config.shown_number(span.start + 1))?;
+        }
+        let text = &line[span.clone()];
+        if config.color == ColorChoice::Always {
+            writeln!(out, "{}", output::highlight(text, std::slice::from_ref(&(0..text.len()))))?;
+        } else {
+            writeln!(out, "{}", text)?;
+        }
+    }
+    if shown < spans.len() {
+        writeln!(out, "{}:(+{} more)", line_num, spans.len() - shown)?;
+    }
+    Ok(())
+}
+
+/// Prints the `--annotate` line of `^` under the `spans` of `line`, indented
+/// past the `prefix` printed before the line. Nothing is printed without
+/// `--annotate` or spans.
+fn write_carets<W: Write>(out: &mut W, config: &Config, prefix: &str, line: &str, spans: &[Range<usize>]) -> io::Result<()> {
+    if !config.annotate || spans.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "{}{}", " ".repeat(prefix.len()), output::carets(line, spans))
+}
+
+/// Prints `num:line`, wrapped under the line's first column with `--wrap`.
+fn write_numbered<W: Write>(out: &mut W, config: &Config, line_num: usize, line: &str) -> io::Result<()> {
+    let prefix = format!("{}:", config.shown_number(line_num));
+    match config.wrap.width() {
+        Some(width) => writeln!(out, "{}{}", prefix, output::wrap(line, prefix.len(), width)),
+        None => writeln!(out, "{}{}", prefix, line),
+    }
+}
+
+/// How many replacements `--replace` makes across the matching lines of
+/// `contents`.
+fn count_replacements(config: &Config, matcher: &Matcher, contents: &str) -> usize {
+    let replacement = config.replace.as_deref().unwrap_or_default();
+    search_numbered(matcher, contents).into_iter()
+                                      .map(|(_, line)| matcher.replace(line, replacement).1)
+                                      .sum()
+}
+
+/// How many replacements `--replace` makes across the matching lines of one
+/// file's recursive results.
+fn replacements_in(config: &Config, matcher: &Matcher, group: &[FileMatch]) -> usize {
+    let replacement = config.replace.as_deref().unwrap_or_default();
+    group.iter().filter(|m| m.is_match).map(|m| matcher.replace(&m.line, replacement).1).sum()
+}
+
+/// Prints the `--replace-count` summary that follows the results of several
+/// files: each file as `path:count`, then the total.
+fn write_replacement_summary<W: Write>(out: &mut W, printed: bool, replaced: &[(String, usize)]) -> io::Result<()> {
+    if printed {
+        writeln!(out)?;
+    }
+    for (path, count) in replaced {
+        writeln!(out, "{}:{}", path, count)?;
+    }
+    writeln!(out, "{} replacements", replaced.iter().map(|(_, count)| count).sum::<usize>())
+}
+
+/// Prints how many replacements `--replace` would make, for
+/// `--count-replacements`: per file as `path:count`, then the total. With
+/// `-r` only the files with replacements are listed, and with one file only
+/// the total is printed.
+fn write_replacement_counts<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    let replacement = config.replace.as_deref().unwrap_or_default();
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    if config.recursive {
+        let timings = Timings::new(false, false);
+        stream_tree(config, matcher, &timings, OnUnreadable::from_config(config), walk_roots(config)?, |group| {
+            *found += group.iter().filter(|m| m.is_match).count();
+            counts.push((shown_path(config, &group[0].path).display().to_string(), replacements_in(config, matcher, &group)));
+            Ok(())
+        })?;
+        counts.sort();
+    } else {
+        for filename in &config.filenames {
+            let contents = read_input(config, filename)?;
+            let lines = search_numbered(matcher, &contents);
+            *found += lines.len();
+            counts.push((filename.clone(), lines.into_iter().map(|(_, line)| matcher.replace(line, replacement).1).sum()));
+        }
+    }
+
+    if config.recursive || counts.len() > 1 {
+        for (path, count) in &counts {
+            writeln!(out, "{}:{}", path, count)?;
+        }
+    }
+    writeln!(out, "{} replacements", counts.iter().map(|(_, count)| count).sum::<usize>())?;
+    Ok(())
+}
+
+/// Prints the longest matching line of each file with a match, as
+/// `path:num (N chars): line`, then the longest of those overall. A single
+/// file gets just `num (N chars): line`.
+fn write_longest<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    let mut longest: Vec<(String, usize, usize, String)> = Vec::new();
+    if config.recursive {
+        let timings = Timings::new(false, false);
+        stream_tree(config, matcher, &timings, OnUnreadable::from_config(config), walk_roots(config)?, |group| {
+            *found += group.iter().filter(|m| m.is_match).count();
+            if let Some((line_num, chars, line)) = longest_line(group.iter().map(|m| (m.line_num, m.line.as_str()))) {
+                longest.push((shown_path(config, &group[0].path).display().to_string(), line_num, chars, line.to_string()));
+            }
+            Ok(())
+        })?;
+        longest.sort();
+    } else {
+        for filename in &config.filenames {
+            let contents = read_input(config, filename)?;
+            let lines = search_numbered(matcher, &contents);
+            *found += lines.len();
+            if let Some((line_num, chars, line)) = longest_line(lines) {
+                longest.push((filename.clone(), line_num, chars, line.to_string()));
+            }
+        }
+    }
+
+    if !config.recursive && config.filenames.len() == 1 {
+        for (_, line_num, chars, line) in &longest {
+            writeln!(out, "{} ({} chars): {}", config.shown_number(*line_num), chars, paint(config, matcher, line))?;
+        }
+        return Ok(());
+    }
+
+    for (path, line_num, chars, line) in &longest {
+        writeln!(out, "{}:{} ({} chars): {}", path, config.shown_number(*line_num), chars, paint(config, matcher, line))?;
+    }
+    if let Some((path, line_num, chars, _)) = longest.iter().reduce(|first, next| if next.2 > first.2 { next } else { first }) {
+        writeln!(out, "Longest overall: {}:{} ({} chars)", path, config.shown_number(*line_num), chars)?;
+    }
+    Ok(())
+}
+
+/// The longest of the numbered `lines` in characters, as its line number,
+/// length and text. Of lines that tie, the first wins.
+pub fn longest_line<'a, I: IntoIterator<Item = (usize, &'a str)>>(lines: I) -> Option<(usize, usize, &'a str)> {
+    lines.into_iter()
+         .map(|(line_num, line)| (line_num, line.chars().count(), line))
+         .reduce(|first, next| if next.1 > first.1 { next } else { first })
+}
+
+/// Prints matching lines of each file searched as raw bytes. Lines are
+/// printed lossily, with invalid UTF-8 replaced, and prefixed with the file
+/// name when there's more than one file.
+fn write_bytes_results<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    for filename in &config.filenames {
+        let contents = file::read_input_bytes(filename, config.max_runtime)?;
+        for (_, line) in search_bytes(matcher, &contents) {
+            *found += 1;
+            if config.filenames.len() > 1 {
+                write!(out, "{}:", filename)?;
+            }
+            writeln!(out, "{}", String::from_utf8_lossy(line))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Version of the `--json` output, bumped whenever its shape changes.
+pub const JSON_VERSION: u32 = 1;
+
+/// Prints every match, with its position details, as a single JSON document:
+///
+/// ```text
+/// {"version": 1, "matches": [...], "summary": {"files_searched": N, "files_matched": N, "matches": N}}
+/// ```
+///
+/// Each match is a `FileMatch::to_json` object. Matches are in path and line
+/// order.
+fn write_json<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    let mut results = Vec::new();
+    let files_searched = if config.recursive {
+        let searched = pipeline(walk_roots(config)?, &config.cancel, OnUnreadable::from_config(config), |path, contents| {
+            search_file(path, contents, matcher, true)
+        }, |group| {
+            results.extend(group);
+            Ok(())
+        });
+        results.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+        searched?
+    } else {
+        for filename in &config.filenames {
+            let contents = read_input(config, filename)?;
+            results.extend(search_file(Path::new(filename), &contents, matcher, true));
+        }
+        config.filenames.len()
+    };
+
+    *found += results.len();
+    let files_matched = results.chunk_by(|a, b| a.path == b.path).count();
+    let matches: Vec<String> = results.iter()
+                                      .map(|m| {
+                                          let shown = FileMatch {
+                                              line_num: config.shown_number(m.line_num),
+                                              column: m.column.map(|column| config.shown_number(column)),
+                                              ..m.clone()
+                                          };
+                                          shown.to_json()
+                                      })
+                                      .collect();
+    writeln!(
+        out,
+        "{{\"version\": {}, \"matches\": [{}], \"summary\": {{\"files_searched\": {}, \"files_matched\": {}, \"matches\": {}}}}}",
+        JSON_VERSION,
+        matches.join(", "),
+        files_searched,
+        files_matched,
+        results.len()
+    )?;
+
+    Ok(())
+}
+
+/// Prints a single line like `3 matches in 2 files (scanned 10 files)`
+/// instead of the matches.
+fn write_summary_line<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    let mut matches = 0;
+    let mut files_matched = 0;
+    let files_searched = if config.recursive {
+        search_files_streaming(walk_roots(config)?, matcher, &config.cancel, |group| {
+            matches += group.len();
+            files_matched += 1;
+            Ok(())
+        })?
+    } else {
+        for filename in &config.filenames {
+            let found = search_numbered(matcher, &read_input(config, filename)?).len();
+            matches += found;
+            files_matched += (found > 0) as usize;
+        }
+        config.filenames.len()
+    };
+
+    *found += matches;
+    let count = |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    writeln!(
+        out,
+        "{} in {} (scanned {})",
+        count(matches, "match", "matches"),
+        count(files_matched, "file", "files"),
+        count(files_searched, "file", "files")
+    )?;
+
+    Ok(())
+}
+
+/// Prints just the line number of each match, prefixed with `path:` when
+/// there's more than one file or the search is recursive. Recursive results
+/// are printed in path order.
+fn write_line_numbers<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    if config.recursive {
+        let mut results = Vec::new();
+        let searched = search_files_streaming(walk_roots(config)?, matcher, &config.cancel, |group| {
+            results.extend(group);
+            Ok(())
+        });
+
+        results.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+        *found += results.len();
+        for m in results {
+            let shown = shown_path(config, &m.path).display().to_string();
+            writeln!(out, "{}:{}", linked(config, &shown, &m.path, Some(m.line_num)), config.shown_number(m.line_num))?;
+        }
+        searched?;
+        return Ok(());
+    }
+
+    for filename in &config.filenames {
+        let contents = read_input(config, filename)?;
+        for (line_num, _) in search_numbered(matcher, &contents) {
+            *found += 1;
+            if config.filenames.len() > 1 {
+                write!(out, "{}:", linked(config, filename, Path::new(filename), Some(line_num)))?;
+            }
+            writeln!(out, "{}", config.shown_number(line_num))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the number of distinct matching lines. A single explicit file gets
+/// a bare number; otherwise each file with matches is listed as
+/// `path:count`, counting lines distinct within that file, followed by
+/// `Total: N`, counting lines distinct across all the files.
+fn write_unique_counts<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    let mut results = Vec::new();
+    if config.recursive {
+        let timings = Timings::new(false, false);
+        stream_tree(config, matcher, &timings, OnUnreadable::from_config(config), walk_roots(config)?, |group| {
+            results.extend(group.into_iter().filter(|m| m.is_match));
+            Ok(())
+        })?;
+        results.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+    } else {
+        for filename in &config.filenames {
+            let contents = read_input(config, filename)?;
+            results.extend(search_file(Path::new(filename), &contents, matcher, false));
+        }
+    }
+
+    *found += results.len();
+    let distinct = |group: &[FileMatch]| group.iter().map(|m| m.line.as_str()).collect::<HashSet<_>>().len();
+
+    if let ([_], false) = (config.filenames.as_slice(), config.recursive) {
+        writeln!(out, "{}", distinct(&results))?;
+        return Ok(());
+    }
+
+    for group in results.chunk_by(|a, b| a.path == b.path) {
+        writeln!(out, "{}:{}", shown_path(config, &group[0].path).display(), distinct(group))?;
+    }
+    writeln!(out, "Total: {}", distinct(&results))?;
+
+    Ok(())
+}
+
+/// Prints the number of matching lines. A single explicit file gets a bare
+/// number; otherwise every file is listed as `path:count`, including files
+/// with no matches unless `--no-zero-counts` is set.
+fn write_counts<W: Write>(out: &mut W, config: &Config, matcher: &Matcher, found: &mut usize) -> Result<(), Error> {
+    let mut counts: Vec<(PathBuf, usize)> = Vec::new();
+
+    if config.recursive {
+        // Every file walked, in the order walked, so those without a match
+        // are listed too.
+        let mut walked = Vec::new();
+        let paths = walk_roots(config)?.inspect(|path| walked.push(path.clone()));
+        let mut matched: HashMap<PathBuf, usize> = HashMap::new();
+        let timings = Timings::new(false, false);
+        stream_tree(config, matcher, &timings, OnUnreadable::from_config(config), paths, |group| {
+            matched.insert(group[0].path.clone(), group.iter().filter(|m| m.is_match).count());
+            Ok(())
+        })?;
+        counts.extend(walked.into_iter().map(|path| {
+            let count = matched.get(&path).copied().unwrap_or(0);
+            (path, count)
+        }));
+    } else {
+        for filename in &config.filenames {
+            let contents = read_input(config, filename)?;
+            counts.push((PathBuf::from(filename), search_with(matcher, &contents).len()));
+        }
+    }
+
+    let total: usize = counts.iter().map(|&(_, count)| count).filter(|&count| count >= config.min_matches).sum();
+    *found += total;
+    if config.total_only {
+        writeln!(out, "{}", total)?;
+        return Ok(());
+    }
+
+    if let ([(_, count)], false) = (counts.as_slice(), config.recursive) {
+        writeln!(out, "{}", count)?;
+        return Ok(());
+    }
+
+    for (path, count) in counts {
+        if (count > 0 || !config.no_zero_counts) && count >= config.min_matches {
+            writeln!(out, "{}:{}", linked(config, &shown_path(config, &path).display().to_string(), &path, None), count)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str>{
+    contents.lines()
+            .filter(|line| line.contains(query))
+            .collect()
+}
+
+pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str>{
+    let query = query.to_lowercase();
+    let mut results = Vec::new();
+
+    for line in contents.lines() {
+        if line.to_lowercase().contains(&query) {
+            results.push(line)
+        }
+    }
+
+    results
+}
+
+/// Whether any line of `contents` contains `query`. Stops at the first
+/// match, so it's cheaper than `search` when the lines themselves aren't
+/// needed.
+pub fn contains(query: &str, contents: &str, case_sensitive: bool) -> bool {
+    lines_contain(query, contents.lines(), case_sensitive)
+}
+
+/// `contains` over any sequence of lines.
+pub fn lines_contain<'a, I>(query: &str, lines: I, case_sensitive: bool) -> bool
+    where I: IntoIterator<Item = &'a str>
+{
+    if case_sensitive {
+        return lines.into_iter().any(|line| line.contains(query));
+    }
+
+    let query = query.to_lowercase();
+    lines.into_iter().any(|line| line.to_lowercase().contains(&query))
+}
+
+/// Like `contains`, but `pattern` is a regex matched against each line.
+pub fn contains_regex(pattern: &str, contents: &str, case_sensitive: bool) -> Result<bool, regex::Error> {
+    let re = regex::RegexBuilder::new(pattern)
+        .case_insensitive(!case_sensitive)
+        .build()?;
+    Ok(contents.lines().any(|line| re.is_match(line)))
+}
+
+pub fn search_with<'a>(matcher: &Matcher, contents: &'a str) -> Vec<&'a str> {
+    search_numbered(matcher, contents).into_iter()
+                                      .map(|(_, line)| line)
+                                      .collect()
+}
+
+/// Splits the lines `1..=total_lines` into up to `buckets` ranges of equal
+/// size, the last possibly shorter, and counts how many of `line_nums` fall
+/// in each. A file with fewer lines than `buckets` gets one range per line.
+pub fn histogram(line_nums: &[usize], total_lines: usize, buckets: usize) -> Vec<(RangeInclusive<usize>, usize)> {
+    if total_lines == 0 || buckets == 0 {
+        return Vec::new();
+    }
+
+    let size = total_lines.div_ceil(buckets.min(total_lines));
+    (0..total_lines.div_ceil(size)).map(|i| {
+                                       let range = i * size + 1..=((i + 1) * size).min(total_lines);
+                                       let count = line_nums.iter().filter(|n| range.contains(n)).count();
+                                       (range, count)
+                                   })
+                                   .collect()
+}
+
+/// Like `search_with`, but keeps each line's 1-based line number.
+pub fn search_numbered<'a>(matcher: &Matcher, contents: &'a str) -> Vec<(usize, &'a str)> {
+    let matcher = matcher.for_contents(contents);
+    contents.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line))
+            .filter(|&(line_num, line)| matcher.is_match_at(line_num, line))
+            .collect()
+}
+
+/// Searches `contents` without requiring it to be valid UTF-8, returning the
+/// line number and raw bytes of each matching line. Lines are split on `\n`
+/// with a trailing `\r` dropped, like `str::lines`.
+pub fn search_bytes<'a>(matcher: &Matcher, contents: &'a [u8]) -> Vec<(usize, &'a [u8])> {
+    let contents = contents.strip_suffix(b"\n").unwrap_or(contents);
+    if contents.is_empty() {
+        return Vec::new();
+    }
+
+    contents.split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .enumerate()
+            .map(|(i, line)| (i + 1, line))
+            .filter(|&(line_num, line)| matcher.in_range(line_num) && matcher.is_match_bytes(line))
+            .collect()
+}
+
+/// Parses a comma-separated list of `START:END` line ranges. Either bound may
+/// be left out (`20:` runs to the end of the file, `:5` starts at line 1), and
+/// a lone `N` means just that line.
+pub fn parse_line_ranges(list: &str) -> Result<Vec<(usize, Option<usize>)>, &'static str> {
+    let parse = |n: &str| n.parse::<usize>().map_err(|_| "Invalid --line-range");
+
+    list.split(',')
+        .map(|range| {
+            let (start, end) = match range.split_once(':') {
+                Some((start, "")) => (start, None),
+                Some((start, end)) => (start, Some(parse(end)?)),
+                None => (range, Some(parse(range)?)),
+            };
+            let start = if start.is_empty() { 1 } else { parse(start)? };
+
+            if start == 0 || end.is_some_and(|end| end < start) {
+                return Err("Invalid --line-range");
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+/// A run of non-blank lines. `start` is the line number of its first line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paragraph<'a> {
+    pub start: usize,
+    pub lines: Vec<&'a str>,
+}
+
+/// Splits `contents` into paragraphs separated by one or more blank (or
+/// whitespace-only) lines.
+pub fn split_paragraphs(contents: &str) -> Vec<Paragraph<'_>> {
+    let mut paragraphs: Vec<Paragraph> = Vec::new();
+    let mut in_paragraph = false;
+
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            in_paragraph = false;
+        } else if in_paragraph {
+            paragraphs.last_mut().unwrap().lines.push(line);
+        } else {
+            paragraphs.push(Paragraph { start: i + 1, lines: vec![line] });
+            in_paragraph = true;
+        }
+    }
+
+    paragraphs
+}
+
+/// Returns the sentence around each match, in order and without repeats. A
+/// sentence ends at `.`, `!` or `?` followed by whitespace or the end of the
+/// text, and may span lines. Where no sentence end turns up within
+/// `SENTENCE_SCAN` bytes of the match, that side stops at the line instead.
+pub fn search_sentences<'a>(matcher: &Matcher, contents: &'a str) -> Vec<&'a str> {
+    let bytes = contents.as_bytes();
+    let ends_sentence = |i: usize| {
+        matches!(bytes[i], b'.' | b'!' | b'?')
+            && bytes.get(i + 1).is_none_or(|b| b.is_ascii_whitespace())
+    };
+
+    let mut sentences: Vec<&str> = Vec::new();
+    for (_, line) in search_numbered(matcher, contents) {
+        let line_start = line.as_ptr() as usize - contents.as_ptr() as usize;
+        let line_end = line_start + line.len();
+
+        for span in matcher.find_spans(line) {
+            let (start, end) = (line_start + span.start, line_start + span.end);
+
+            let start = (start.saturating_sub(SENTENCE_SCAN)..start).rev()
+                                                                   .find(|&i| ends_sentence(i))
+                                                                   .map_or(line_start, |i| i + 1);
+            let end = (end..bytes.len().min(end + SENTENCE_SCAN)).find(|&i| ends_sentence(i))
+                                                                 .map_or(line_end, |i| i + 1);
+
+            let sentence = contents[start..end].trim();
+            if sentences.last() != Some(&sentence) {
+                sentences.push(sentence);
+            }
+        }
+    }
+
+    sentences
+}
+
+/// Returns the paragraphs in which at least one line matches.
+pub fn search_paragraphs<'a>(matcher: &Matcher, contents: &'a str) -> Vec<Paragraph<'a>> {
+    let matcher = matcher.for_contents(contents);
+    split_paragraphs(contents).into_iter()
+                              .filter(|p| {
+                                  p.lines.iter()
+                                         .enumerate()
+                                         .any(|(i, line)| matcher.is_match_at(p.start + i, line))
+                              })
+                              .collect()
+}
+
+/// A line of context output. Only matching lines carry `spans`, the byte
+/// ranges of each occurrence of the query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextLine<'a> {
+    pub line_num: usize,
+    pub line: &'a str,
+    pub is_match: bool,
+    pub spans: Vec<Range<usize>>,
+}
+
+/// Returns each matching line together with up to `before` lines before and
+/// `after` lines after it, in file order. Overlapping windows are merged, so
+/// every line appears at most once.
+pub fn search_context<'a>(matcher: &Matcher, contents: &'a str, before: usize, after: usize) -> Vec<ContextLine<'a>> {
+    let matcher = matcher.for_contents(contents);
+    let lines: Vec<&str> = contents.lines().collect();
+    let spans = line_spans(&matcher, &lines);
+
+    with_context(&matcher, &lines, spans, before, after)
+}
+
+/// Like `search_context`, but every match keeps its own window of context,
+/// so lines shared by nearby matches are repeated instead of merged.
+pub fn search_context_groups<'a>(matcher: &Matcher, contents: &'a str, before: usize, after: usize) -> Vec<Vec<ContextLine<'a>>> {
+    let matcher = matcher.for_contents(contents);
+    let lines: Vec<&str> = contents.lines().collect();
+    let spans = line_spans(&matcher, &lines);
+
+    spans.iter()
+         .enumerate()
+         .filter(|(_, s)| s.is_some())
+         .map(|(i, _)| {
+             matcher.context_window(&lines, i, before, after)
+                    .map(|k| ContextLine {
+                        line_num: k + 1,
+                        line: lines[k],
+                        is_match: spans[k].is_some(),
+                        spans: spans[k].clone().unwrap_or_default(),
+                    })
+                    .collect()
+         })
+         .collect()
+}
+
+/// Like `search_context_groups`, but each window is `size` lines, centered
+/// on its match and moved to fit inside the file when the match is near the
+/// start or end. Windows are only shorter than `size` when the whole file is.
+pub fn search_context_fill<'a>(matcher: &Matcher, contents: &'a str, size: usize) -> Vec<Vec<ContextLine<'a>>> {
+    let matcher = matcher.for_contents(contents);
+    let lines: Vec<&str> = contents.lines().collect();
+    let spans = line_spans(&matcher, &lines);
+
+    spans.iter()
+         .enumerate()
+         .filter(|(_, s)| s.is_some())
+         .map(|(i, _)| {
+             let start = i.saturating_sub((size - 1) / 2).min(lines.len().saturating_sub(size));
+             let end = (start + size).min(lines.len());
+             (start..end).map(|k| ContextLine {
+                             line_num: k + 1,
+                             line: lines[k],
+                             is_match: spans[k].is_some(),
+                             spans: spans[k].clone().unwrap_or_default(),
+                         })
+                         .collect()
+         })
+         .collect()
+}
+
+/// The spans of each matching line, or `None` for lines that don't match.
+fn line_spans(matcher: &Matcher, lines: &[&str]) -> Vec<Option<Vec<Range<usize>>>> {
+    lines.iter()
+         .enumerate()
+         .map(|(i, line)| {
+             if matcher.is_match_at(i + 1, line) { Some(matcher.find_spans(line)) } else { None }
+         })
+         .collect()
+}
+
+/// Matches the query against the whole of `contents` rather than line by
+/// line, so a regex can match across line breaks. Each match is reported on
+/// the line it starts on or, with `whole_span`, on every line it covers, with
+/// context lines around those as in `search_context`.
+pub fn search_multiline<'a>(matcher: &Matcher, contents: &'a str, whole_span: bool, before: usize, after: usize) -> Vec<ContextLine<'a>> {
+    let mut starts = Vec::new();
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for raw in contents.split_inclusive('\n') {
+        let line = raw.strip_suffix('\n').unwrap_or(raw);
+        starts.push(offset);
+        lines.push(line.strip_suffix('\r').unwrap_or(line));
+        offset += raw.len();
+    }
+    if lines.is_empty() {
+        return Vec::new();
+    }
+
+    let line_of = |pos: usize| starts.partition_point(|&start| start <= pos) - 1;
+    let mut spans: Vec<Option<Vec<Range<usize>>>> = vec![None; lines.len()];
+
+    for m in matcher.find_spans(contents) {
+        let first = line_of(m.start);
+        // A match that ends with a newline doesn't reach into the next line.
+        let last = if whole_span { line_of(m.end.saturating_sub(1).max(m.start)) } else { first };
+
+        for i in first..=last {
+            let line_start = starts[i];
+            let line_end = line_start + lines[i].len();
+            let start = m.start.clamp(line_start, line_end) - line_start;
+            let end = m.end.clamp(line_start, line_end) - line_start;
+
+            let line_spans = spans[i].get_or_insert_with(Vec::new);
+            if start < end {
+                line_spans.push(start..end);
+            }
+        }
+    }
+
+    with_context(matcher, &lines, spans, before, after)
+}
+
+/// Keeps the matching lines, those with `Some` spans, along with up to
+/// `before` lines before and `after` lines after each. Overlapping windows
+/// are merged.
+fn with_context<'a>(matcher: &Matcher, lines: &[&'a str], mut spans: Vec<Option<Vec<Range<usize>>>>, before: usize, after: usize) -> Vec<ContextLine<'a>> {
+    let mut keep = vec![false; lines.len()];
+    for (i, _) in spans.iter().enumerate().filter(|(_, s)| s.is_some()) {
+        for k in &mut keep[matcher.context_window(lines, i, before, after)] {
+            *k = true;
+        }
+    }
+
+    lines.iter()
+         .enumerate()
+         .filter(|(i, _)| keep[*i])
+         .map(|(i, line)| {
+             let line_spans = spans[i].take();
+             ContextLine {
+                 line_num: i + 1,
+                 line,
+                 is_match: line_spans.is_some(),
+                 spans: line_spans.unwrap_or_default(),
+             }
+         })
+         .collect()
+}
+
+/// Returns every line of `contents`, flagging (and giving spans for) the
+/// matching ones, as if context were unlimited.
+pub fn search_passthru<'a>(matcher: &Matcher, contents: &'a str) -> Vec<ContextLine<'a>> {
+    let matcher = matcher.for_contents(contents);
+    contents.lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let is_match = matcher.is_match_at(i + 1, line);
+                ContextLine {
+                    line_num: i + 1,
+                    line,
+                    is_match,
+                    spans: if is_match { matcher.find_spans(line) } else { Vec::new() },
+                }
+            })
+            .collect()
+}
+
+/// How `--sort-by-relevance` ranks a line, or a file by the matching lines
+/// in it, given each line with the spans of its matches: first by the number
+/// of matches, then by how much of the lines' text they cover, in thousandths.
+/// Higher is more relevant.
+pub fn relevance<'a, I>(lines: I) -> (usize, usize)
+    where I: IntoIterator<Item = (&'a str, Vec<Range<usize>>)>
+{
+    let (mut matches, mut covered, mut len) = (0, 0, 0);
+    for (line, spans) in lines {
+        matches += spans.len();
+        covered += spans.iter().map(|span| span.len()).sum::<usize>();
+        len += line.len();
+    }
+    (matches, (covered * 1000).checked_div(len).unwrap_or(0))
+}
+
+/// Reorders matched lines by `key`. The sort is stable, so lines that compare
+/// equal keep their file order. Sorting only applies to matched lines, so any
+/// key other than `SortKey::Line` turns context off.
+pub fn sort_lines(lines: &mut [&str], key: SortKey) {
+    sort_by_text(lines, key, |line| line);
+}
+
+fn sort_by_text<T, F>(items: &mut [T], key: SortKey, text: F)
+    where F: Fn(&T) -> &str
+{
+    match key {
+        SortKey::Line => {}
+        SortKey::Alpha => items.sort_by(|a, b| text(a).cmp(text(b))),
+        SortKey::LineLength => items.sort_by_key(|item| text(item).chars().count()),
+    }
+}
+
+/// A matching line found while searching a tree. The position details are
+/// only filled in when the output needs them; build one with
+/// `..FileMatch::default()` to leave them out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cache", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileMatch {
+    pub path: PathBuf,
+    pub line_num: usize,
+    pub line: String,
+    /// `false` for context lines.
+    pub is_match: bool,
+    /// 1-based byte column of the first match in the line.
+    pub column: Option<usize>,
+    /// Byte offset of the start of the line in the file.
+    pub byte_offset: Option<usize>,
+    /// Byte ranges of each match within `line`.
+    pub match_spans: Vec<Range<usize>>,
+}
+
+impl FileMatch {
+    /// Fills in the position details for `line`, a slice of `contents`.
+    fn with_details(mut self, contents: &str, line: &str, spans: Vec<Range<usize>>) -> FileMatch {
+        self.column = spans.first().map(|span| span.start + 1);
+        self.byte_offset = Some(line.as_ptr() as usize - contents.as_ptr() as usize);
+        self.match_spans = spans;
+        self
+    }
+
+    /// A JSON object with the match's fields. Position details that weren't
+    /// filled in are left out.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"path\": \"{}\", \"line_num\": {}, \"line\": \"{}\", \"is_match\": {}",
+            error::json_escape(&self.path.to_string_lossy()),
+            self.line_num,
+            error::json_escape(&self.line),
+            self.is_match
+        );
+        if let Some(column) = self.column {
+            json.push_str(&format!(", \"column\": {}", column));
+        }
+        if let Some(byte_offset) = self.byte_offset {
+            json.push_str(&format!(", \"byte_offset\": {}", byte_offset));
+        }
+        if !self.match_spans.is_empty() {
+            let spans: Vec<String> = self.match_spans
+                                         .iter()
+                                         .map(|span| format!("[{}, {}]", span.start, span.end))
+                                         .collect();
+            json.push_str(&format!(", \"match_spans\": [{}]", spans.join(", ")));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// `path:line_num:line`, with the column after the line number when known.
+impl fmt::Display for FileMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:", self.path.display(), self.line_num)?;
+        if let Some(column) = self.column {
+            write!(f, "{}:", column)?;
+        }
+        write!(f, "{}", self.line)
+    }
+}
+
+/// What `search_stream` found: the matching lines, along with their context
+/// lines when context is set, in stream order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchResults {
+    pub lines: Vec<FileMatch>,
+    /// Files passed over without being searched, sorted by path.
+    pub warnings: Vec<Warning>,
+}
+
+/// A file a search passed over rather than searching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The file looks binary.
+    Binary(PathBuf),
+    /// The file couldn't be read as UTF-8 text, with why.
+    Unreadable(PathBuf, String),
+}
+
+impl Warning {
+    pub fn path(&self) -> &Path {
+        match self {
+            Warning::Binary(path) | Warning::Unreadable(path, _) => path,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::Binary(path) => write!(f, "{}: skipped binary file", path.display()),
+            Warning::Unreadable(path, reason) => write!(f, "{}: skipped, {}", path.display(), reason),
+        }
+    }
+}
+
+/// What `pipeline` does with a file it can't read as UTF-8 text.
+#[derive(Debug, Clone, Copy)]
+enum OnUnreadable<'a> {
+    Skip,
+    /// Skip it, noting why in a list of warnings.
+    Record(&'a Mutex<Vec<Warning>>),
+    /// Stop the search with the file's error.
+    Stop,
+}
+
+impl OnUnreadable<'_> {
+    /// Stops with `--fail-fast`, and skips otherwise.
+    fn from_config(config: &Config) -> OnUnreadable<'static> {
+        if config.fail_fast { OnUnreadable::Stop } else { OnUnreadable::Skip }
+    }
+}
+
+impl SearchResults {
+    /// The matching lines, without context.
+    pub fn matches(&self) -> impl Iterator<Item = &FileMatch> {
+        self.lines.iter().filter(|line| line.is_match)
+    }
+
+    /// Whether nothing at all was found, not even context.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Whether any line matched.
+    pub fn matched(&self) -> bool {
+        self.matches().next().is_some()
+    }
+}
+
+/// The result of `search_and_report`, telling a search that found nothing
+/// apart from one that found matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Matches(SearchResults),
+    NoMatches,
+}
+
+/// Searches the files `config` names, or with `-r` the trees under them,
+/// and collects the matches with their context and position details, sorted
+/// by path and line number. Like `any_match`, which backs `--quiet`, this
+/// only finds lines; output options such as `--count` don't apply. The
+/// files a recursive search skips, as binary or unreadable, are listed in
+/// the results' `warnings`.
+pub fn search_and_report(config: &Config) -> Result<Outcome, Error> {
+    let matcher = Matcher::new(config)?;
+    let mut results = SearchResults::default();
+
+    if config.recursive {
+        let binary = Arc::new(Mutex::new(Vec::new()));
+        let unreadable = Mutex::new(Vec::new());
+        let on_unreadable = if config.fail_fast { OnUnreadable::Stop } else { OnUnreadable::Record(&unreadable) };
+        let paths = walk_roots_recording(config, Some(&binary))?;
+        stream_tree(config, &matcher, &Timings::new(false, false), on_unreadable, paths, |group| {
+            results.lines.extend(group);
+            Ok(())
+        })?;
+        results.lines.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+
+        results.warnings = binary.lock().unwrap().drain(..).map(Warning::Binary).collect();
+        results.warnings.extend(unreadable.into_inner().unwrap());
+        results.warnings.sort_by(|a, b| a.path().cmp(b.path()));
+    } else {
+        let (before, after) = config.context();
+        for filename in &config.filenames {
+            let contents = read_input(config, filename)?;
+            results.lines.extend(search_file_context(Path::new(filename), &contents, &matcher, before, after, true));
+        }
+    }
+
+    Ok(if results.matched() { Outcome::Matches(results) } else { Outcome::NoMatches })
+}
+
+/// Searches a stream as `config` asks, for data that isn't in a file. Lines
+/// are reported with `-` as their path, like standard input, and with their
+/// position details filled in.
+///
+/// Without context, `--within-tag` or `--section-start` the stream is read a
+/// line at a time, so only the current line and the matches are held in
+/// memory. Those need to look around each line, so then the whole stream is
+/// read into memory first. Output modes such as `--count` or `--paragraph`
+/// don't apply here.
+pub fn search_stream<R: Read>(reader: R, config: &Config) -> Result<SearchResults, Error> {
+    let matcher = Matcher::new(config)?;
+    let path = Path::new("-");
+    let (before, after) = config.context();
+    let mut reader = BufReader::new(reader);
+
+    if before > 0 || after > 0 || config.within_tag.is_some() || config.section_start.is_some() {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        return Ok(SearchResults {
+            lines: search_file_context(path, &contents, &matcher, before, after, true),
+            ..SearchResults::default()
+        });
+    }
+
+    let mut results = SearchResults::default();
+    let mut buf = String::new();
+    let mut offset = 0;
+    for line_num in 1.. {
+        buf.clear();
+        let read = reader.read_line(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+
+        let line = buf.strip_suffix('\n').unwrap_or(&buf);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if matcher.is_match_at(line_num, line) {
+            let spans = matcher.find_spans(line);
+            results.lines.push(FileMatch {
+                path: path.to_path_buf(),
+                line_num,
+                line: line.to_string(),
+                is_match: true,
+                column: spans.first().map(|span| span.start + 1),
+                byte_offset: Some(offset),
+                match_spans: spans,
+            });
+        }
+        offset += read;
+    }
+
+    Ok(results)
+}
+
+/// Whether any of `paths` contains a match, or with `min_matches` above 1,
+/// that many matching lines, along with how many files were searched. The
+/// first such file found sets `cancel` to stop the whole search,
+/// so in a large tree only the files already in flight are read after it.
+/// `cancel` being set some other way, such as by Ctrl-C, before anything
+/// matched is reported as `Error::Cancelled`.
+pub fn any_file_matches<I>(paths: I, matcher: &Matcher, min_matches: usize, cancel: &AtomicBool) -> Result<(bool, usize), Error>
+    where I: Iterator<Item = PathBuf> + Send
+{
+    let found = AtomicBool::new(false);
+    let searched = pipeline(paths, cancel, OnUnreadable::Skip, |path, contents| {
+        let matched = if min_matches > 1 {
+            let all = search_file(path, contents, matcher, false);
+            if all.len() >= min_matches { all } else { Vec::new() }
+        } else {
+            first_match(path, contents, matcher)
+        };
+        if !matched.is_empty() {
+            found.store(true, Ordering::SeqCst);
+            cancel.store(true, Ordering::SeqCst);
+        }
+        matched
+    }, |_| Ok(()));
+
+    match searched {
+        Ok(files_searched) => Ok((false, files_searched)),
+        Err(Error::Cancelled { files_searched }) if found.load(Ordering::SeqCst) => Ok((true, files_searched)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Searches `paths` with a pool of reader threads feeding file contents
+/// through a bounded channel to a pool of searcher threads, so IO and
+/// matching overlap. Files that can't be read as UTF-8 text are skipped.
+/// Results are sorted by path and line number, so the output doesn't depend
+/// on thread scheduling.
+pub fn search_files(paths: &[PathBuf], matcher: &Matcher) -> Vec<FileMatch> {
+    let never_cancelled = AtomicBool::new(false);
+    let mut results = Vec::new();
+    search_files_streaming(paths.iter().cloned(), matcher, &never_cancelled, |group| {
+        results.extend(group);
+        Ok(())
+    }).expect("collecting results can't fail");
+
+    results.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+    results
+}
+
+/// Like `search_files`, but pulls paths lazily and hands each file's matches
+/// to `each` on the calling thread as soon as that file is searched, so
+/// discovery, searching and printing interleave. Files are reported in
+/// completion order; files without matches aren't reported. An error from
+/// `each` stops the search and is returned.
+///
+/// Setting `cancel` stops the search between files: files already being
+/// searched finish and are reported, then `Error::Cancelled` is returned.
+/// Otherwise returns the number of files searched.
+pub fn search_files_streaming<I, F>(paths: I, matcher: &Matcher, cancel: &AtomicBool, each: F) -> Result<usize, Error>
+    where I: Iterator<Item = PathBuf> + Send,
+          F: FnMut(Vec<FileMatch>) -> Result<(), Error>
+{
+    pipeline(paths, cancel, OnUnreadable::Skip, |path, contents| search_file(path, contents, matcher, false), each)
+}
+
+/// Counts how many of `paths` contain at least one match. Each file is only
+/// searched up to its first match.
+pub fn count_matching_files<I>(paths: I, matcher: &Matcher, cancel: &AtomicBool) -> Result<usize, Error>
+    where I: Iterator<Item = PathBuf> + Send
+{
+    let mut matched = 0;
+    pipeline(paths, cancel, OnUnreadable::Skip, |path, contents| first_match(path, contents, matcher), |_| {
+        matched += 1;
+        Ok(())
+    })?;
+    Ok(matched)
+}
+
+/// The reader/searcher pipeline behind `search_files_streaming`, with the
+/// per-file search supplied by the caller. Files that can't be read are
+/// handled as `on_unreadable` says; when they stop the search, the first
+/// one's error is returned once the files already searched have been
+/// reported.
+fn pipeline<I, S, F>(paths: I, cancel: &AtomicBool, on_unreadable: OnUnreadable, search: S, mut each: F) -> Result<usize, Error>
+    where I: Iterator<Item = PathBuf> + Send,
+          S: Fn(&Path, &str) -> Vec<FileMatch> + Sync,
+          F: FnMut(Vec<FileMatch>) -> Result<(), Error>
+{
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let queue = Mutex::new(paths);
+    let searched = AtomicUsize::new(0);
+    let failed: Mutex<Option<io::Error>> = Mutex::new(None);
+    let stopped = || cancel.load(Ordering::SeqCst) || failed.lock().unwrap().is_some();
+    let (loaded_tx, loaded_rx) = mpsc::sync_channel::<(PathBuf, String)>(PIPELINE_DEPTH);
+    let (found_tx, found_rx) = mpsc::channel();
+    // Searchers share ownership of the receiver, so it's dropped (and readers
+    // stop) as soon as they've all exited.
+    let loaded_rx = Arc::new(Mutex::new(loaded_rx));
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            let loaded_tx = loaded_tx.clone();
+            let queue = &queue;
+            let failed = &failed;
+            let stopped = &stopped;
+            s.spawn(move || loop {
+                if stopped() {
+                    break;
+                }
+                let path = match queue.lock().unwrap().next() {
+                    Some(path) => path,
+                    None => break,
+                };
+                match file::read_file(&path) {
+                    Ok(contents) => {
+                        if loaded_tx.send((path, contents)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => match on_unreadable {
+                        OnUnreadable::Skip => {}
+                        OnUnreadable::Record(warnings) => {
+                            warnings.lock().unwrap().push(Warning::Unreadable(path, err.to_string()));
+                        }
+                        OnUnreadable::Stop => {
+                            let err = io::Error::new(err.kind(), format!("{}: {}", path.display(), err));
+                            failed.lock().unwrap().get_or_insert(err);
+                            break;
+                        }
+                    },
+                }
+            });
+        }
+        drop(loaded_tx);
+
+        for _ in 0..workers {
+            let loaded_rx = Arc::clone(&loaded_rx);
+            let found_tx = found_tx.clone();
+            let searched = &searched;
+            let search = &search;
+            let stopped = &stopped;
+            s.spawn(move || loop {
+                if stopped() {
+                    break;
+                }
+                let received = loaded_rx.lock().unwrap().recv();
+                let (path, contents) = match received {
+                    Ok(loaded) => loaded,
+                    Err(_) => break,
+                };
+                let found = search(&path, &contents);
+                searched.fetch_add(1, Ordering::SeqCst);
+                if !found.is_empty() && found_tx.send(found).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(loaded_rx);
+        drop(found_tx);
+
+        found_rx.into_iter().try_for_each(&mut each)
+    })?;
+
+    let files_searched = searched.load(Ordering::SeqCst);
+    if cancel.load(Ordering::SeqCst) {
+        return Err(Error::Cancelled { files_searched });
+    }
+    if let Some(err) = failed.into_inner().unwrap() {
+        return Err(Error::Io(err));
+    }
+    Ok(files_searched)
+}
+
+/// Finds the matching lines of one file, with their position details when
+/// `details` is set.
+fn search_file(path: &Path, contents: &str, matcher: &Matcher, details: bool) -> Vec<FileMatch> {
+    let matcher = matcher.for_contents(contents);
+    contents.lines()
+            .enumerate()
+            .filter(|(i, line)| matcher.is_match_at(i + 1, line))
+            .map(|(i, line)| {
+                let found = FileMatch {
+                    path: path.to_path_buf(),
+                    line_num: i + 1,
+                    line: line.to_string(),
+                    is_match: true,
+                    ..FileMatch::default()
+                };
+                if details { found.with_details(contents, line, matcher.find_spans(line)) } else { found }
+            })
+            .collect()
+}
+
+fn search_file_context(path: &Path, contents: &str, matcher: &Matcher, before: usize, after: usize, details: bool) -> Vec<FileMatch> {
+    search_context(matcher, contents, before, after).into_iter()
+                                                    .map(|line| {
+                                                        let found = FileMatch {
+                                                            path: path.to_path_buf(),
+                                                            line_num: line.line_num,
+                                                            line: line.line.to_string(),
+                                                            is_match: line.is_match,
+                                                            ..FileMatch::default()
+                                                        };
+                                                        if details { found.with_details(contents, line.line, line.spans) } else { found }
+                                                    })
+                                                    .collect()
+}
+
+fn first_match(path: &Path, contents: &str, matcher: &Matcher) -> Vec<FileMatch> {
+    let matcher = matcher.for_contents(contents);
+    contents.lines()
+            .enumerate()
+            .find(|(i, line)| matcher.is_match_at(i + 1, line))
+            .map(|(i, line)| FileMatch {
+                path: path.to_path_buf(),
+                line_num: i + 1,
+                line: line.to_string(),
+                is_match: true,
+                ..FileMatch::default()
+            })
+            .into_iter()
+            .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Line,
+    Alpha,
+    LineLength,
+}
+
+impl SortKey {
+    fn parse(key: &str) -> Result<SortKey, &'static str> {
+        match key {
+            "line" => Ok(SortKey::Line),
+            "alpha" => Ok(SortKey::Alpha),
+            "line-length" => Ok(SortKey::LineLength),
+            _ => Err("Unknown --sort-lines key"),
+        }
+    }
+}
+
+/// What `--max-runtime` running out means: `Error` ends the run with
+/// `Error::TimedOut`, `Partial` treats what was found so far as the result.
+/// Either way the results found in time are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnTimeout {
+    Error,
+    Partial,
+}
+
+impl OnTimeout {
+    fn parse(what: &str) -> Result<OnTimeout, &'static str> {
+        match what {
+            "error" => Ok(OnTimeout::Error),
+            "partial" => Ok(OnTimeout::Partial),
+            _ => Err("Unknown --on-timeout value"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Always,
+    Never,
+    Auto,
+}
+
+impl ColorChoice {
+    fn parse(when: &str) -> Result<ColorChoice, &'static str> {
+        match when {
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            "auto" => Ok(ColorChoice::Auto),
+            _ => Err("Unknown --color value"),
+        }
+    }
+
+    /// Turns `Auto` into `Always` when writing to a terminal that can show
+    /// colors, and `Never` otherwise. Following <https://no-color.org>, a
+    /// non-empty `NO_COLOR` rules colors out, as does `TERM=dumb`.
+    pub fn resolve(self, is_terminal: bool) -> ColorChoice {
+        self.resolve_with(is_terminal, env::var_os("NO_COLOR").as_deref(), env::var_os("TERM").as_deref())
+    }
+
+    /// `resolve`, given the values of `NO_COLOR` and `TERM`.
+    fn resolve_with(self, is_terminal: bool, no_color: Option<&OsStr>, term: Option<&OsStr>) -> ColorChoice {
+        let colors = is_terminal && no_color.is_none_or(OsStr::is_empty) && term != Some(OsStr::new("dumb"));
+        match self {
+            ColorChoice::Auto if colors => ColorChoice::Always,
+            ColorChoice::Auto => ColorChoice::Never,
+            choice => choice,
+        }
+    }
+}
+
+/// A pattern given with `-e` or `-E=`, in place of the query argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryPattern {
+    Literal(String),
+    Regex(String),
+}
+
+/// What `--expand-env` does with a variable that isn't set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndefinedVar {
+    Empty,
+    Error,
+}
+
+impl UndefinedVar {
+    fn parse(undefined: &str) -> Result<UndefinedVar, &'static str> {
+        match undefined {
+            "empty" => Ok(UndefinedVar::Empty),
+            "error" => Ok(UndefinedVar::Error),
+            _ => Err("Unknown --expand-env value"),
+        }
+    }
+}
+
+/// Replaces each `${VAR}` in `pattern` with the value of the environment
+/// variable `VAR`. A `${` with no closing `}` is left as it is.
+pub fn expand_env(pattern: &str, undefined: UndefinedVar) -> Result<String, ConfigError> {
+    let mut expanded = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else { break };
+        let name = &rest[start + 2..start + 2 + len];
+
+        expanded.push_str(&rest[..start]);
+        match (env::var(name), undefined) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(_), UndefinedVar::Empty) => {}
+            (Err(_), UndefinedVar::Error) => return Err(ConfigError::UndefinedVariable(name.to_string())),
+        }
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Whether `--wrap` wraps long lines: to the terminal's width, only when
+/// writing to a terminal, or to a given width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapChoice {
+    Off,
+    Auto,
+    Width(usize),
+}
+
+impl WrapChoice {
+    /// Turns `Auto` into the terminal's width, or `Off` when not writing to
+    /// a terminal or its width can't be found.
+    pub fn resolve(self, is_terminal: bool) -> WrapChoice {
+        match self {
+            WrapChoice::Auto if is_terminal => terminal_width().map_or(WrapChoice::Off, WrapChoice::Width),
+            WrapChoice::Auto => WrapChoice::Off,
+            choice => choice,
+        }
+    }
+
+    pub fn width(self) -> Option<usize> {
+        match self {
+            WrapChoice::Width(width) => Some(width),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "wrap")]
+fn terminal_width() -> Option<usize> {
+    terminal_size::terminal_size().map(|(terminal_size::Width(width), _)| width as usize)
+}
+
+/// Without the `wrap` feature there's no way to ask the terminal.
+#[cfg(not(feature = "wrap"))]
+fn terminal_width() -> Option<usize> {
+    None
+}
+
+/// How paths found by `-r` are printed: as walked from the root given on the
+/// command line, relative to that root, or absolute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathFormat {
+    AsWalked,
+    Relative,
+    Absolute,
+}
+
+impl PathFormat {
+    fn parse(format: &str) -> Result<PathFormat, &'static str> {
+        match format {
+            "relative" => Ok(PathFormat::Relative),
+            "absolute" => Ok(PathFormat::Absolute),
+            _ => Err("Unknown --path-format value"),
+        }
+    }
+}
+
+pub struct Config {
+    pub query: String,
+    pub filenames: Vec<String>,
+    pub case_sensitive: bool,
+    pub and_not: Vec<String>,
+    pub sort_lines: SortKey,
+    pub error_format: ErrorFormat,
+    pub recursive: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub color: ColorChoice,
+    pub paragraph: bool,
+    pub count: bool,
+    pub no_zero_counts: bool,
+    pub regex: bool,
+    pub byte_mode: bool,
+    pub sort_files: bool,
+    pub passthru: bool,
+    pub line_ranges: Vec<(usize, Option<usize>)>,
+    pub multiline: bool,
+    pub multiline_span: bool,
+    pub field: Option<usize>,
+    pub delimiter: Option<char>,
+    /// Set, e.g. from a Ctrl-C handler, to stop a multi-file search between
+    /// files.
+    pub cancel: Arc<AtomicBool>,
+    pub only_count_files: bool,
+    pub max_line_bytes: Option<usize>,
+    pub line_numbers_only: bool,
+    /// Prompt for queries on standard input instead of taking one argument.
+    pub repl: bool,
+    pub no_merge_context: bool,
+    pub path_format: PathFormat,
+    /// Printed in place of each occurrence of the query in matching lines.
+    pub replace: Option<String>,
+    pub replace_count: bool,
+    /// Only match lines inside this XML/HTML element.
+    pub within_tag: Option<String>,
+    /// Print nothing; the exit status says whether anything matched.
+    pub quiet: bool,
+    pub json: bool,
+    /// Search hidden files and directories with `-r`.
+    pub hidden: bool,
+    pub sentence_context: bool,
+    pub timing: bool,
+    /// Patterns from `-e` and `-E=`. When there are any, they replace the
+    /// query and a line matches if any one of them does.
+    pub patterns: Vec<QueryPattern>,
+    /// Ask before each `--replace` replacement, then edit the files in place.
+    pub replace_interactive: bool,
+    /// Expand `${VAR}` in the query, and what to do about unset variables.
+    pub expand_env: Option<UndefinedVar>,
+    pub summary_line: bool,
+    pub wrap: WrapChoice,
+    pub count_unique: bool,
+    pub only_matching: bool,
+    pub column: bool,
+    /// With `-o`, the most matches printed for any one line.
+    pub max_matches_per_line: Option<usize>,
+    /// An expression deciding which lines match, in place of the query.
+    /// Needs the `script` feature.
+    pub script: Option<String>,
+    /// Lines printed before and after each record: a match, or a group of
+    /// matches and their context.
+    pub record_start: Option<String>,
+    pub record_end: Option<String>,
+    /// Search files as they were at this git revision. Needs the `git`
+    /// feature.
+    pub git_rev: Option<String>,
+    /// Select the lines that don't match, which then take the place of
+    /// matches everywhere, context included.
+    pub invert: bool,
+    /// Encodings to try, in order, when decoding each file. Needs the
+    /// `encoding` feature.
+    pub try_encodings: Vec<String>,
+    pub histogram: bool,
+    /// Head each context group with the range of lines it covers.
+    pub context_header: bool,
+    /// Print lines without their leading whitespace. Matching and reported
+    /// columns still use the whole line.
+    pub trim: bool,
+    /// Keep a byte-order mark at the start of a file as part of its first
+    /// line. It's dropped by default.
+    pub no_strip_bom: bool,
+    /// Build regexes without Unicode support, so classes like `\w` and `.`
+    /// and case folding only know about ASCII.
+    pub no_unicode: bool,
+    /// Print paths relative to this directory, overriding `path_format`.
+    pub relative_to: Option<PathBuf>,
+    /// Print the most relevant lines, or with `-r` files, first.
+    pub sort_by_relevance: bool,
+    /// Context stops short of lines equal to this, so it stays within the
+    /// record a match is in.
+    pub context_boundary: Option<String>,
+    /// After the results, print how many files and bytes were searched, how
+    /// long it took, and the throughput.
+    pub stats: bool,
+    /// With `-r`, print files in the reverse of their sorted order.
+    pub reverse: bool,
+    /// With `-r`, follow each `File:` heading with the file's type, such as
+    /// `[rust]`.
+    pub show_type: bool,
+    /// Print runs of blank result lines as a single one.
+    pub squeeze_blank: bool,
+    /// Only match lines inside sections, which begin after a line matching
+    /// this pattern.
+    pub section_start: Option<String>,
+    /// Ends a section begun by `section_start`; otherwise sections run until
+    /// the next one starts.
+    pub section_end: Option<String>,
+    /// With colors on, link printed paths to this URL, in which `{path}` is
+    /// the file's absolute path and `{line}` a line number.
+    pub hyperlink: Option<String>,
+    /// With `-o`, also report matches that overlap earlier ones.
+    pub overlapping: bool,
+    /// With `-r`, prefix every line with its path instead of grouping lines
+    /// under `File:` headings.
+    pub flat: bool,
+    /// With `-r`, leave out files with fewer matching lines than this.
+    pub min_matches: usize,
+    /// Print each file's matches and context as one quoted excerpt, with
+    /// `...` between groups.
+    pub snippet: bool,
+    /// With `-r`, stop at the first file that can't be read rather than
+    /// skipping it.
+    pub fail_fast: bool,
+    /// The byte that ends each line of input and output, a newline by
+    /// default. Must be ASCII. Only files named on the command line are
+    /// split on it, so it can't be combined with `-r`.
+    pub line_terminator: u8,
+    /// Exit with an error status when at least this many lines match.
+    pub fail_if_matches: Option<usize>,
+    /// Exit with an error status when nothing matches.
+    pub fail_if_no_matches: bool,
+    /// Print only this matching line of the run, counting from 1 across
+    /// files in the order they're searched.
+    pub nth: Option<usize>,
+    /// Never match lines whose first non-blank characters are the comment
+    /// prefix.
+    pub no_comments: bool,
+    /// What starts a comment line for `--no-comments`, `#` when unset.
+    pub comment_prefix: Option<String>,
+    /// With `-r`, search a file reachable through several links only once.
+    pub dedupe_files: bool,
+    /// Replace matches with nothing, like `--replace=`, leaving out the
+    /// lines that are then empty.
+    pub delete: bool,
+    /// With `--delete`, print the lines it empties as blank lines.
+    pub keep_empty: bool,
+    /// Follow each matching line with a line of `^` under its matches.
+    pub annotate: bool,
+    /// Write the results to this file instead of standard output.
+    pub output_file: Option<PathBuf>,
+    /// Let `--output-file` replace a file being searched.
+    pub force: bool,
+    /// With `--count`, print only the total across all files.
+    pub total_only: bool,
+    /// Make each `--replace` follow the case of the text it replaces.
+    pub preserve_case: bool,
+    /// Print matching lines as `num:line`, as context output does, even
+    /// without context.
+    pub show_context_markers: bool,
+    /// Only match in files where each `-e` pattern matches at least once,
+    /// not necessarily on the same line.
+    pub all_patterns: bool,
+    /// Print how many replacements `--replace` would make instead of
+    /// making them.
+    pub count_replacements: bool,
+    /// Stop searching further files once this much time has passed.
+    pub max_runtime: Option<Duration>,
+    /// Whether running out of `--max-runtime` is an error or just ends the
+    /// results early.
+    pub on_timeout: OnTimeout,
+    /// Print line and column numbers counting from 0 instead of 1.
+    pub zero_based: bool,
+    /// With `-r`, head each file's lines with just `path:` and leave out
+    /// the blank line between files.
+    pub compact: bool,
+    /// Print only the longest matching line of each file, and the longest
+    /// of all.
+    pub longest: bool,
+    /// Show each match in a window of exactly this many lines.
+    pub context_fill: Option<usize>,
+    /// Only match lines at least this long, in characters.
+    pub min_line_length: Option<usize>,
+    /// Only match lines at most this long, in characters.
+    pub max_line_length: Option<usize>,
+    /// Measure `min_line_length` and `max_line_length` in bytes instead.
+    pub line_length_bytes: bool,
+    /// With `-r`, keep each file's results here and reuse them while the
+    /// file is unchanged. Needs the `cache` feature.
+    pub cache_dir: Option<PathBuf>,
+    /// With several files or `-r`, list the files searched without a match
+    /// as `path: (no matches)`.
+    pub print_empty: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            query: String::new(),
+            filenames: Vec::new(),
+            case_sensitive: true,
+            and_not: Vec::new(),
+            sort_lines: SortKey::Line,
+            error_format: ErrorFormat::Text,
+            recursive: false,
+            before_context: 0,
+            after_context: 0,
+            color: ColorChoice::Auto,
+            paragraph: false,
+            count: false,
+            no_zero_counts: false,
+            regex: false,
+            byte_mode: false,
+            sort_files: false,
+            passthru: false,
+            line_ranges: Vec::new(),
+            multiline: false,
+            multiline_span: false,
+            field: None,
+            delimiter: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            only_count_files: false,
+            max_line_bytes: None,
+            line_numbers_only: false,
+            repl: false,
+            no_merge_context: false,
+            path_format: PathFormat::AsWalked,
+            replace: None,
+            replace_count: false,
+            within_tag: None,
+            quiet: false,
+            json: false,
+            hidden: false,
+            sentence_context: false,
+            timing: false,
+            patterns: Vec::new(),
+            replace_interactive: false,
+            expand_env: None,
+            summary_line: false,
+            wrap: WrapChoice::Off,
+            count_unique: false,
+            only_matching: false,
+            column: false,
+            max_matches_per_line: None,
+            script: None,
+            record_start: None,
+            record_end: None,
+            git_rev: None,
+            invert: false,
+            try_encodings: Vec::new(),
+            histogram: false,
+            context_header: false,
+            trim: false,
+            no_strip_bom: false,
+            no_unicode: false,
+            relative_to: None,
+            sort_by_relevance: false,
+            context_boundary: None,
+            stats: false,
+            reverse: false,
+            show_type: false,
+            squeeze_blank: false,
+            section_start: None,
+            section_end: None,
+            hyperlink: None,
+            overlapping: false,
+            flat: false,
+            min_matches: 0,
+            snippet: false,
+            fail_fast: false,
+            fail_if_matches: None,
+            fail_if_no_matches: false,
+            nth: None,
+            no_comments: false,
+            comment_prefix: None,
+            dedupe_files: false,
+            delete: false,
+            keep_empty: false,
+            annotate: false,
+            output_file: None,
+            force: false,
+            total_only: false,
+            preserve_case: false,
+            show_context_markers: false,
+            all_patterns: false,
+            count_replacements: false,
+            max_runtime: None,
+            on_timeout: OnTimeout::Error,
+            zero_based: false,
+            compact: false,
+            longest: false,
+            context_fill: None,
+            min_line_length: None,
+            max_line_length: None,
+            line_length_bytes: false,
+            cache_dir: None,
+            print_empty: false,
+            line_terminator: b'\n',
+        }
+    }
+}
+
+impl Config {
+    pub fn new<I>(args: I) -> Result<Config, ConfigError>
+        where I: Iterator<Item = String>
+    {
+        #[allow(unused_mut)]
+        let mut config = Config::default();
+
+        #[cfg(feature = "config-file")]
+        if let Some(path) = rc::find() {
+            rc::load(&path)?.apply(&mut config)?;
+        }
+
+        config.parse_args(args)
+    }
+
+    /// Applies the command-line `args`, starting with the program name, on
+    /// top of the defaults already in `self`.
+    fn parse_args<I>(mut self, mut args: I) -> Result<Config, ConfigError>
+        where I: Iterator<Item = String>
+    {
+        args.next();
+
+        let config = &mut self;
+        let mut positional = Vec::new();
+
+        for arg in args {
+            if !config.apply_option(&arg)? {
+                positional.push(arg);
+            }
+        }
+
+        // In the REPL queries are typed at the prompt, so a lone argument is
+        // the file, as it is when line lengths alone pick the lines. With
+        // `-e`, `-E=` or `--script`, every argument is a file.
+        let by_length = config.min_line_length.is_some() || config.max_line_length.is_some();
+        if ((config.repl || by_length) && positional.len() == 1) || !config.patterns.is_empty() || config.script.is_some() {
+            positional.insert(0, String::new());
+        }
+
+        let mut positional = positional.into_iter();
+        config.query = match positional.next() {
+            Some(arg) => arg,
+            None => return Err(ConfigError::Invalid("Didn't get a query string")),
+        };
+        // Expand globs the shell left alone, unless a file has that very name.
+        // A glob that matches nothing is kept, to fail as a missing file.
+        config.filenames = positional.flat_map(|filename| {
+                                         let expanded = if file::is_glob(&filename) && !Path::new(&filename).exists() {
+                                             file::expand_glob(&filename)
+                                         } else {
+                                             Vec::new()
+                                         };
+                                         if expanded.is_empty() {
+                                             vec![filename]
+                                         } else {
+                                             expanded.into_iter().map(|path| path.to_string_lossy().into_owned()).collect()
+                                         }
+                                     })
+                                     .collect();
+        if config.filenames.is_empty() {
+            return Err(ConfigError::Invalid("Didn't get a file name"));
+        }
+
+        if let Some(undefined) = config.expand_env {
+            config.query = expand_env(&config.query, undefined)?;
+            for pattern in &mut config.patterns {
+                let (QueryPattern::Literal(p) | QueryPattern::Regex(p)) = pattern;
+                *p = expand_env(p, undefined)?;
+            }
+        }
+
+        if env::var("CASE_INSENSITIVE").is_ok() {
+            config.case_sensitive = false;
+        }
+
+        config.validate()?;
+        Ok(self)
+    }
+
+    /// Applies a single command-line option. Returns `false` if `arg` isn't an
+    /// option and should be treated as a positional argument.
+    pub(crate) fn apply_option(&mut self, arg: &str) -> Result<bool, ConfigError> {
+        if let Some(pattern) = arg.strip_prefix("--and-not=") {
+            self.and_not.push(pattern.to_string());
+        } else if let Some(key) = arg.strip_prefix("--sort-lines=") {
+            self.sort_lines = SortKey::parse(key)?;
+        } else if let Some(format) = arg.strip_prefix("--error-format=") {
+            self.error_format = ErrorFormat::parse(format)?;
+        } else if arg == "-r" || arg == "--recursive" {
+            self.recursive = true;
+        } else if let Some(n) = arg.strip_prefix("-C=").or_else(|| arg.strip_prefix("--context=")) {
+            let n = n.parse().map_err(|_| "Invalid context value")?;
+            self.before_context = n;
+            self.after_context = n;
+        } else if let Some(n) = arg.strip_prefix("-B=").or_else(|| arg.strip_prefix("--before-context=")) {
+            self.before_context = n.parse().map_err(|_| "Invalid context value")?;
+        } else if let Some(n) = arg.strip_prefix("-A=").or_else(|| arg.strip_prefix("--after-context=")) {
+            self.after_context = n.parse().map_err(|_| "Invalid context value")?;
+        } else if arg == "--color" {
+            self.color = ColorChoice::Always;
+        } else if let Some(when) = arg.strip_prefix("--color=") {
+            self.color = ColorChoice::parse(when)?;
+        } else if arg == "--paragraph" {
+            self.paragraph = true;
+        } else if arg == "-c" || arg == "--count" {
+            self.count = true;
+        } else if arg == "--no-zero-counts" {
+            self.no_zero_counts = true;
+        } else if let Some(pattern) = arg.strip_prefix("-e=").or_else(|| arg.strip_prefix("--literal=")) {
+            self.patterns.push(QueryPattern::Literal(pattern.to_string()));
+        } else if let Some(pattern) = arg.strip_prefix("-E=").or_else(|| arg.strip_prefix("--regex=")) {
+            self.patterns.push(QueryPattern::Regex(pattern.to_string()));
+        } else if arg == "-E" || arg == "--regex" {
+            self.regex = true;
+        } else if arg == "--byte-mode" {
+            self.byte_mode = true;
+        } else if arg == "--sort=path" {
+            self.sort_files = true;
+        } else if arg == "--passthru" {
+            self.passthru = true;
+        } else if let Some(list) = arg.strip_prefix("--line-range=") {
+            self.line_ranges = parse_line_ranges(list)?;
+        } else if arg == "-U" || arg == "--multiline" {
+            self.multiline = true;
+        } else if arg == "--multiline-span" {
+            self.multiline = true;
+            self.multiline_span = true;
+        } else if let Some(n) = arg.strip_prefix("--field=") {
+            match n.parse() {
+                Ok(n) if n > 0 => self.field = Some(n),
+                _ => return Err(ConfigError::Invalid("Invalid --field value")),
+            }
+        } else if let Some(delimiter) = arg.strip_prefix("--delimiter=") {
+            let mut chars = delimiter.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => self.delimiter = Some(c),
+                _ => return Err(ConfigError::Invalid("--delimiter must be a single character")),
+            }
+        } else if arg == "--only-count-files" {
+            self.only_count_files = true;
+        } else if let Some(n) = arg.strip_prefix("--max-line-bytes=") {
+            self.max_line_bytes = Some(n.parse().map_err(|_| "Invalid --max-line-bytes value")?);
+        } else if arg == "--timing" {
+            self.timing = true;
+        } else if arg == "--sentence-context" {
+            self.sentence_context = true;
+        } else if arg == "--hidden" {
+            self.hidden = true;
+        } else if arg == "--no-hidden" {
+            self.hidden = false;
+        } else if arg == "--json" {
+            self.json = true;
+        } else if arg == "-q" || arg == "--quiet" {
+            self.quiet = true;
+        } else if let Some(tag) = arg.strip_prefix("--within-tag=") {
+            if tag.is_empty() {
+                return Err(ConfigError::Invalid("--within-tag needs a tag name"));
+            }
+            self.within_tag = Some(tag.to_string());
+        } else if let Some(replacement) = arg.strip_prefix("--replace=") {
+            self.replace = Some(replacement.to_string());
+        } else if let Some(script) = arg.strip_prefix("--script=") {
+            if cfg!(not(feature = "script")) {
+                return Err(ConfigError::Invalid("--script needs minigrep built with the script feature"));
+            }
+            self.script = Some(script.to_string());
+        } else if arg == "-o" || arg == "--only-matching" {
+            self.only_matching = true;
+        } else if arg == "--column" {
+            self.column = true;
+        } else if let Some(n) = arg.strip_prefix("--max-matches-per-line=") {
+            self.max_matches_per_line = Some(n.parse().map_err(|_| "Invalid --max-matches-per-line value")?);
+        } else if arg == "--count-unique" {
+            self.count_unique = true;
+        } else if arg == "--wrap" {
+            self.wrap = WrapChoice::Auto;
+        } else if let Some(width) = arg.strip_prefix("--wrap=") {
+            match width.parse() {
+                Ok(width) if width > 0 => self.wrap = WrapChoice::Width(width),
+                _ => return Err(ConfigError::Invalid("Invalid --wrap width")),
+            }
+        } else if arg == "--summary-line" {
+            self.summary_line = true;
+        } else if arg == "--expand-env" {
+            self.expand_env = Some(UndefinedVar::Empty);
+        } else if let Some(undefined) = arg.strip_prefix("--expand-env=") {
+            self.expand_env = Some(UndefinedVar::parse(undefined)?);
+        } else if arg == "--replace-interactive" {
+            self.replace_interactive = true;
+        } else if arg == "--replace-count" {
+            self.replace_count = true;
+        } else if let Some(format) = arg.strip_prefix("--path-format=") {
+            self.path_format = PathFormat::parse(format)?;
+        } else if arg == "--reverse" {
+            self.reverse = true;
+        } else if arg == "--show-type" {
+            self.show_type = true;
+        } else if arg == "--squeeze-blank" {
+            self.squeeze_blank = true;
+        } else if let Some(pattern) = arg.strip_prefix("--section-start=") {
+            self.section_start = Some(pattern.to_string());
+        } else if let Some(pattern) = arg.strip_prefix("--section-end=") {
+            self.section_end = Some(pattern.to_string());
+        } else if let Some(n) = arg.strip_prefix("--min-matches=") {
+            self.min_matches = n.parse().map_err(|_| "Invalid --min-matches value")?;
+        } else if arg == "-z" || arg == "--null-data" {
+            self.line_terminator = b'\0';
+        } else if let Some(terminator) = arg.strip_prefix("--line-terminator=") {
+            self.line_terminator = match terminator {
+                "\\0" => b'\0',
+                "\\t" => b'\t',
+                "\\r" => b'\r',
+                "\\n" => b'\n',
+                _ if terminator.len() == 1 && terminator.is_ascii() => terminator.as_bytes()[0],
+                _ => return Err(ConfigError::Invalid("--line-terminator needs a single ASCII character")),
+            };
+        } else if arg == "--fail-if-matches" {
+            self.fail_if_matches = Some(1);
+        } else if let Some(n) = arg.strip_prefix("--fail-if-matches=") {
+            self.fail_if_matches = Some(n.parse().map_err(|_| "Invalid --fail-if-matches value")?);
+        } else if let Some(path) = arg.strip_prefix("--output-file=") {
+            self.output_file = Some(PathBuf::from(path));
+        } else if let Some(secs) = arg.strip_prefix("--max-runtime=") {
+            let secs: f64 = secs.parse().map_err(|_| "Invalid --max-runtime value")?;
+            self.max_runtime = Some(Duration::try_from_secs_f64(secs).map_err(|_| "Invalid --max-runtime value")?);
+        } else if let Some(what) = arg.strip_prefix("--on-timeout=") {
+            self.on_timeout = OnTimeout::parse(what)?;
+        } else if let Some(n) = arg.strip_prefix("--min-line-length=") {
+            self.min_line_length = Some(n.parse().map_err(|_| "Invalid --min-line-length value")?);
+        } else if let Some(n) = arg.strip_prefix("--max-line-length=") {
+            self.max_line_length = Some(n.parse().map_err(|_| "Invalid --max-line-length value")?);
+        } else if arg == "--cache" || arg.starts_with("--cache-dir=") {
+            if cfg!(not(feature = "cache")) {
+                return Err(ConfigError::Invalid("--cache needs minigrep built with the cache feature"));
+            }
+            #[cfg(feature = "cache")]
+            {
+                self.cache_dir = Some(arg.strip_prefix("--cache-dir=").map_or_else(cache::default_dir, PathBuf::from));
+            }
+        } else if arg == "--print-empty" {
+            self.print_empty = true;
+        } else if arg == "--line-length-bytes" {
+            self.line_length_bytes = true;
+        } else if let Some(n) = arg.strip_prefix("--context-fill=") {
+            self.context_fill = Some(n.parse().map_err(|_| "Invalid --context-fill value")?);
+        } else if arg == "--longest" {
+            self.longest = true;
+        } else if arg == "--compact" {
+            self.compact = true;
+        } else if arg == "--zero-based" {
+            self.zero_based = true;
+        } else if arg == "--count-replacements" {
+            self.count_replacements = true;
+        } else if arg == "--all-patterns" {
+            self.all_patterns = true;
+        } else if arg == "--show-context-markers" {
+            self.show_context_markers = true;
+        } else if arg == "--preserve-case" {
+            self.preserve_case = true;
+        } else if arg == "--total-only" {
+            self.total_only = true;
+        } else if arg == "--force" {
+            self.force = true;
+        } else if arg == "--annotate" {
+            self.annotate = true;
+        } else if arg == "--delete" {
+            self.delete = true;
+            self.replace = Some(String::new());
+        } else if arg == "--keep-empty" {
+            self.keep_empty = true;
+        } else if arg == "--dedupe-files" {
+            self.dedupe_files = true;
+        } else if arg == "--no-comments" {
+            self.no_comments = true;
+        } else if let Some(prefix) = arg.strip_prefix("--comment-prefix=") {
+            self.comment_prefix = Some(prefix.to_string());
+        } else if let Some(n) = arg.strip_prefix("--nth=") {
+            self.nth = Some(n.parse().map_err(|_| "Invalid --nth value")?);
+        } else if arg == "--fail-if-no-matches" {
+            self.fail_if_no_matches = true;
+        } else if arg == "--fail-fast" {
+            self.fail_fast = true;
+        } else if arg == "--snippet" {
+            self.snippet = true;
+        } else if arg == "--flat" {
+            self.flat = true;
+        } else if arg == "--overlapping" {
+            self.overlapping = true;
+        } else if arg == "--hyperlink" {
+            self.hyperlink.get_or_insert_with(|| HYPERLINK_FORMAT.to_string());
+        } else if let Some(format) = arg.strip_prefix("--hyperlink-format=") {
+            self.hyperlink = Some(format.to_string());
+        } else if arg == "--stats" {
+            self.stats = true;
+        } else if let Some(boundary) = arg.strip_prefix("--context-boundary=") {
+            self.context_boundary = Some(boundary.to_string());
+        } else if arg == "--sort-by-relevance" {
+            self.sort_by_relevance = true;
+        } else if let Some(dir) = arg.strip_prefix("--relative-to=") {
+            self.relative_to = Some(PathBuf::from(dir));
+        } else if arg == "--no-unicode" {
+            self.no_unicode = true;
+        } else if arg == "--no-strip-bom" {
+            self.no_strip_bom = true;
+        } else if arg == "--trim" {
+            self.trim = true;
+        } else if arg == "--context-header" {
+            self.context_header = true;
+        } else if arg == "--histogram" {
+            self.histogram = true;
+        } else if let Some(names) = arg.strip_prefix("--try-encodings=") {
+            if cfg!(not(feature = "encoding")) {
+                return Err(ConfigError::Invalid("--try-encodings needs minigrep built with the encoding feature"));
+            }
+            #[cfg(feature = "encoding")]
+            if names.split(',').any(|name| encoding_rs::Encoding::for_label(name.as_bytes()).is_none()) {
+                return Err(ConfigError::Invalid("--try-encodings has an unknown encoding"));
+            }
+            self.try_encodings = names.split(',').map(str::to_string).collect();
+        } else if arg == "-v" || arg == "--invert-match" {
+            self.invert = true;
+        } else if let Some(rev) = arg.strip_prefix("--git-rev=") {
+            if cfg!(not(feature = "git")) {
+                return Err(ConfigError::Invalid("--git-rev needs minigrep built with the git feature"));
+            }
+            self.git_rev = Some(rev.to_string());
+        } else if let Some(line) = arg.strip_prefix("--record-start=") {
+            self.record_start = Some(line.to_string());
+        } else if let Some(line) = arg.strip_prefix("--record-end=") {
+            self.record_end = Some(line.to_string());
+        } else if arg == "--no-merge-context" {
+            self.no_merge_context = true;
+        } else if arg == "--repl" {
+            self.repl = true;
+        } else if arg == "--line-numbers-only" {
+            self.line_numbers_only = true;
+        } else if arg.starts_with("--") {
+            return Err(ConfigError::Invalid("Unknown option"));
+        } else {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Whether context groups are marked with records or headers instead of
+    /// being separated by `--`.
+    fn marks_groups(&self) -> bool {
+        self.record_start.is_some() || self.record_end.is_some() || self.context_header
+    }
+
+    /// Lines of context to show `(before, after)` each match. Sorting lines
+    /// turns context off.
+    pub fn context(&self) -> (usize, usize) {
+        if self.sort_lines == SortKey::Line {
+            (self.before_context, self.after_context)
+        } else {
+            (0, 0)
+        }
+    }
+
+    /// A line or column number, 1-based everywhere inside minigrep, as it's
+    /// printed: one less with `--zero-based`.
+    pub fn shown_number(&self, n: usize) -> usize {
+        if self.zero_based { n - 1 } else { n }
+    }
+
+    /// Checks for combinations of options that can't be honored together,
+    /// rather than letting one of them silently win.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let options = [
+            ("--count", self.count),
+            ("--passthru", self.passthru),
+            ("--paragraph", self.paragraph),
+            ("--context", self.before_context > 0 || self.after_context > 0),
+            ("--byte-mode", self.byte_mode),
+            ("--recursive", self.recursive),
+            ("--multiline", self.multiline),
+            ("--field", self.field.is_some()),
+            ("--only-count-files", self.only_count_files),
+            ("--line-numbers-only", self.line_numbers_only),
+            ("--repl", self.repl),
+            ("--no-merge-context", self.no_merge_context),
+            ("--replace", self.replace.is_some()),
+            ("--within-tag", self.within_tag.is_some()),
+            ("--section-start", self.section_start.is_some()),
+            ("--quiet", self.quiet),
+            ("--json", self.json),
+            ("--sentence-context", self.sentence_context),
+            ("--timing", self.timing),
+            ("-e", !self.patterns.is_empty()),
+            ("--replace-interactive", self.replace_interactive),
+            ("--summary-line", self.summary_line),
+            ("--count-unique", self.count_unique),
+            ("--only-matching", self.only_matching),
+            ("--script", self.script.is_some()),
+            ("--record-start", self.record_start.is_some()),
+            ("--record-end", self.record_end.is_some()),
+            ("--git-rev", self.git_rev.is_some()),
+            ("--invert-match", self.invert),
+            ("--try-encodings", !self.try_encodings.is_empty()),
+            ("--histogram", self.histogram),
+            ("--context-header", self.context_header),
+            ("--trim", self.trim),
+            ("--no-strip-bom", self.no_strip_bom),
+            ("--sort-lines", self.sort_lines != SortKey::Line),
+            ("--sort-by-relevance", self.sort_by_relevance),
+            ("--stats", self.stats),
+            ("--show-type", self.show_type),
+            ("--flat", self.flat),
+            ("--min-matches", self.min_matches > 0),
+            ("--snippet", self.snippet),
+            ("--line-terminator", self.line_terminator != b'\n'),
+            ("--fail-if-matches", self.fail_if_matches.is_some()),
+            ("--fail-if-no-matches", self.fail_if_no_matches),
+            ("--nth", self.nth.is_some()),
+            ("--annotate", self.annotate),
+            ("--output-file", self.output_file.is_some()),
+            ("--show-context-markers", self.show_context_markers),
+            ("--all-patterns", self.all_patterns),
+            ("--count-replacements", self.count_replacements),
+            ("--replace-count", self.replace_count),
+            ("--max-runtime", self.max_runtime.is_some()),
+            ("--compact", self.compact),
+            ("--longest", self.longest),
+            ("--context-fill", self.context_fill.is_some()),
+            ("--print-empty", self.print_empty),
+        ];
+        // Output modes that print something about the matches in place of
+        // the lines themselves.
+        let summaries = [
+            "--count",
+            "--count-unique",
+            "--count-replacements",
+            "--longest",
+            "--only-count-files",
+            "--line-numbers-only",
+            "--json",
+            "--summary-line",
+        ];
+        // Output modes that pick or lay out the matching lines their own way.
+        let layouts = [
+            "--byte-mode",
+            "--nth",
+            "--paragraph",
+            "--passthru",
+            "--sentence-context",
+            "--multiline",
+            "--only-matching",
+            "--snippet",
+            "--histogram",
+            "--context-fill",
+        ];
+        let output_modes = [&summaries[..], &layouts].concat();
+        // Output modes with no groups of lines for these to mark.
+        let ungrouped = [&summaries[..], &["--byte-mode", "--paragraph", "--sentence-context", "--only-matching", "--snippet", "--histogram"]].concat();
+        let group_marks = ["--annotate", "--context-header", "--record-start", "--record-end", "--show-context-markers"];
+        // Modes that read from the terminal rather than search once.
+        let interactive = ["--repl", "--replace-interactive"];
+        let sorts = ["--sort-lines", "--sort-by-relevance"];
+        let reports = ["--stats", "--timing"];
+        // Options that narrow which lines match beyond the query.
+        let line_filters = ["--all-patterns", "--field", "--script", "--section-start", "--within-tag"];
+        // Options that read files some other way than as UTF-8 text on disk.
+        let decoding = ["--git-rev", "--try-encodings", "--no-strip-bom"];
+        // `--count-replacements` counts what `--replace` would do.
+        let not_replaced: Vec<&str> = output_modes.iter().copied().filter(|&mode| mode != "--count-replacements").collect();
+
+        // At most one option of each of these can be given.
+        let exclusive: [&[&str]; 4] = [&output_modes, &interactive, &sorts, &["--git-rev", "--try-encodings"]];
+        // No option of the first of each pair can be given with any of the
+        // second.
+        let conflicts: [(&[&str], &[&str]); 30] = [
+            (&ungrouped, &group_marks),
+            (&[&summaries[..], &["--byte-mode"]].concat(), &[&reports[..], &["--print-empty"]].concat()),
+            (&interactive, &summaries),
+            (&interactive, &["--quiet", "--output-file", "--fail-if-matches", "--fail-if-no-matches", "--max-runtime", "--line-terminator", "--print-empty", "--timing"]),
+            (&["--replace-interactive"], &layouts),
+            (&["--replace-interactive"], &["--git-rev", "--try-encodings"]),
+            (&["--repl"], &["--annotate", "--recursive", "--script", "--stats", "-e", "--byte-mode", "--nth", "--context-fill"]),
+            (&["--quiet"], &summaries),
+            (&["--quiet"], &reports),
+            (&["--quiet"], &["--multiline", "--output-file", "--fail-if-matches", "--fail-if-no-matches"]),
+            (&["--replace"], &not_replaced),
+            (&["--replace"], &["--annotate", "--context", "--field", "--invert-match", "--script", "--show-context-markers"]),
+            (&["--context"], &summaries),
+            (&["--context"], &["--byte-mode", "--context-fill", "--histogram", "--only-matching", "--paragraph", "--passthru", "--sentence-context", "--sort-by-relevance"]),
+            (&["--byte-mode", "--multiline"], &[&line_filters[..], &["--sort-by-relevance"]].concat()),
+            (&["--byte-mode"], &[&decoding[..], &["--line-terminator", "--recursive"]].concat()),
+            (&["--recursive"], &[&decoding[..], &["--context-fill", "--histogram", "--multiline", "--no-merge-context", "--paragraph", "--passthru", "--sentence-context"]].concat()),
+            (&["--multiline"], &["--annotate", "--invert-match", "--no-merge-context"]),
+            (&["--script"], &["--all-patterns", "-e", "--only-matching"]),
+            (&["--flat"], &["--annotate", "--context-header", "--record-start", "--record-end", "--compact", "--show-type", "--nth", "--only-matching", "--snippet"]),
+            (&sorts, &["--nth", "--show-context-markers"]),
+            (&["--sort-lines"], &["--context-fill", "--snippet"]),
+            (&["--sort-by-relevance"], &["--paragraph", "--passthru", "--print-empty"]),
+            (&["--min-matches"], &["--count-unique", "--json", "--line-numbers-only", "--only-count-files", "--summary-line", "--nth"]),
+            (&["--nth"], &["--annotate", "--fail-if-matches", "--print-empty"]),
+            (&["--invert-match"], &["--only-matching", "--sentence-context"]),
+            (&["--fail-if-matches"], &["--only-count-files"]),
+            (&["--context-fill"], &["--no-merge-context"]),
+            (&["--json"], &["--trim"]),
+            (&["--count-replacements"], &["--replace-count"]),
+        ];
+        let is_set = |name: &str| options.iter().any(|&(option, set)| option == name && set);
+        let conflict = |a, b| ConfigError::ConflictingOptions(format!("{} can't be used with {}", a, b));
+
+        for group in exclusive {
+            if let [a, b, ..] = group.iter().filter(|&&name| is_set(name)).collect::<Vec<_>>()[..] {
+                return Err(conflict(a, b));
+            }
+        }
+        for (group, others) in conflicts {
+            if let (Some(a), Some(b)) = (group.iter().find(|&&name| is_set(name)), others.iter().find(|&&name| is_set(name))) {
+                return Err(conflict(a, b));
+            }
+        }
+
+        // Files found by `-r` are read and searched in bulk, always split on
+        // newlines.
+        if self.line_terminator != b'\n' && self.recursive {
+            return Err(ConfigError::Invalid("--line-terminator only applies to files named on the command line, not to -r"));
+        }
+
+        if self.replace_count && self.replace.is_none() {
+            return Err(ConfigError::Invalid("--replace-count needs --replace"));
+        }
+
+        if self.column && !self.only_matching {
+            return Err(ConfigError::Invalid("--column needs --only-matching"));
+        }
+        if self.max_matches_per_line.is_some() && !self.only_matching {
+            return Err(ConfigError::Invalid("--max-matches-per-line needs --only-matching"));
+        }
+        if self.print_empty && !self.recursive && self.filenames.len() < 2 {
+            return Err(ConfigError::Invalid("--print-empty needs --recursive or several files"));
+        }
+        if self.cache_dir.is_some() && !self.recursive {
+            return Err(ConfigError::Invalid("--cache needs --recursive"));
+        }
+        if self.flat && !self.recursive {
+            return Err(ConfigError::Invalid("--flat needs --recursive"));
+        }
+        if self.compact && !self.recursive {
+            return Err(ConfigError::Invalid("--compact needs --recursive"));
+        }
+        if self.min_matches > 0 && !self.recursive {
+            return Err(ConfigError::Invalid("--min-matches needs --recursive"));
+        }
+        if self.overlapping && !self.only_matching {
+            return Err(ConfigError::Invalid("--overlapping needs --only-matching"));
+        }
+        if self.count_replacements && self.replace.is_none() {
+            return Err(ConfigError::Invalid("--count-replacements needs --replace"));
+        }
+        if self.all_patterns && self.patterns.is_empty() {
+            return Err(ConfigError::Invalid("--all-patterns needs -e"));
+        }
+        if self.preserve_case && self.replace.is_none() {
+            return Err(ConfigError::Invalid("--preserve-case needs --replace"));
+        }
+        if self.preserve_case && (self.regex || self.patterns.iter().any(|p| matches!(p, QueryPattern::Regex(_)))) {
+            return Err(ConfigError::ConflictingOptions("--preserve-case only works with plain-text queries, not -E".to_string()));
+        }
+        if self.on_timeout != OnTimeout::Error && self.max_runtime.is_none() {
+            return Err(ConfigError::Invalid("--on-timeout needs --max-runtime"));
+        }
+        if self.total_only && !self.count {
+            return Err(ConfigError::Invalid("--total-only needs --count"));
+        }
+        if self.force && self.output_file.is_none() {
+            return Err(ConfigError::Invalid("--force needs --output-file"));
+        }
+        if self.delete && self.replace.as_deref() != Some("") {
+            return Err(ConfigError::ConflictingOptions("--delete can't be used with --replace".to_string()));
+        }
+        if self.keep_empty && !self.delete {
+            return Err(ConfigError::Invalid("--keep-empty needs --delete"));
+        }
+        if self.dedupe_files && !self.recursive {
+            return Err(ConfigError::Invalid("--dedupe-files needs --recursive"));
+        }
+        if self.comment_prefix.is_some() && !self.no_comments {
+            return Err(ConfigError::Invalid("--comment-prefix needs --no-comments"));
+        }
+        if self.comment_prefix.as_deref() == Some("") {
+            return Err(ConfigError::Invalid("--comment-prefix can't be empty"));
+        }
+        if self.nth == Some(0) {
+            return Err(ConfigError::Invalid("--nth counts matches from 1"));
+        }
+        if let (Some(min), Some(max)) = (self.min_line_length, self.max_line_length) {
+            if min > max {
+                return Err(ConfigError::Invalid("--min-line-length is more than --max-line-length"));
+            }
+        }
+        if self.line_length_bytes && self.min_line_length.is_none() && self.max_line_length.is_none() {
+            return Err(ConfigError::Invalid("--line-length-bytes needs --min-line-length or --max-line-length"));
+        }
+        if self.context_fill == Some(0) {
+            return Err(ConfigError::Invalid("--context-fill needs a window of at least 1 line"));
+        }
+        if self.section_end.is_some() && self.section_start.is_none() {
+            return Err(ConfigError::Invalid("--section-end needs --section-start"));
+        }
+
+        if self.replace_interactive && self.replace.is_none() {
+            return Err(ConfigError::Invalid("--replace-interactive needs --replace"));
+        }
+
+        // It would write a compressed file back decompressed.
+        if self.replace_interactive && self.filenames.iter().any(|f| file::is_compressed(Path::new(f))) {
+            return Err(ConfigError::Invalid("--replace-interactive can't rewrite a compressed file"));
+        }
+
+        if self.replace_interactive && self.filenames.iter().any(|f| f == "-") {
+            return Err(ConfigError::ConflictingOptions(
+                "--replace-interactive reads answers from standard input, so can't search it".to_string()
+            ));
+        }
+
+        if self.repl && (self.filenames.len() != 1 || self.filenames[0] == "-") {
+            return Err(ConfigError::ConflictingOptions(
+                "--repl needs exactly one file, and reads queries from standard input".to_string()
+            ));
+        }
+
+        if self.recursive && self.filenames.iter().any(|f| f == "-") {
+            return Err(ConfigError::ConflictingOptions(
+                "--recursive can't search standard input".to_string()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a `Config` programmatically, taking options in their command-line
+/// spelling, e.g. `ConfigBuilder::new("needle", "log.txt").option("--count")?`.
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new(query: &str, filename: &str) -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config {
+                query: query.to_string(),
+                filenames: vec![filename.to_string()],
+                ..Config::default()
+            },
+        }
+    }
+
+    pub fn filename(mut self, filename: &str) -> ConfigBuilder {
+        self.config.filenames.push(filename.to_string());
+        self
+    }
+
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> ConfigBuilder {
+        self.config.case_sensitive = case_sensitive;
+        self
+    }
+
+    pub fn option(mut self, option: &str) -> Result<ConfigBuilder, ConfigError> {
+        if !self.config.apply_option(option)? {
+            return Err(ConfigError::Invalid("Unknown option"));
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn config(args: &[&str]) -> Config {
+        let args = ["minigrep"].iter().chain(args).map(|s| s.to_string());
+        Config::new(args).unwrap()
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let query = "duct";
+        let content = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+        assert_eq!(
+            vec!["safe, fast, productive."],
+            search(query, content)
+        );
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let query = "rUsT";
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+        assert_eq!(
+            vec!["Rust:", "Trust me."],
+            search_case_insensitive(query, contents)
+        );
+
+    }
+
+    #[test]
+    fn and_not_excludes_lines() {
+        let contents = "\
+error: disk full
+error: retrying, ignore this
+warning: ignore me too";
+        let config = config(&["--and-not=ignore", "error", "log.txt"]);
+        assert_eq!(
+            vec!["error: disk full"],
+            search_with(&Matcher::new(&config).unwrap(), contents)
+        );
+    }
+
+    #[test]
+    fn and_not_is_repeatable() {
+        let contents = "\
+error: disk full
+error: retrying
+error: ignore this";
+        let config = config(&["error", "log.txt", "--and-not=ignore", "--and-not=retry"]);
+        assert_eq!(vec!["ignore", "retry"], config.and_not);
+        assert_eq!(
+            vec!["error: disk full"],
+            search_with(&Matcher::new(&config).unwrap(), contents)
+        );
+    }
+
+    #[test]
+    fn sort_lines_alpha() {
+        let config = config(&["--sort-lines=alpha", "a", "poem.txt"]);
+        let mut lines = vec!["cat", "apple", "banana"];
+        sort_lines(&mut lines, config.sort_lines);
+        assert_eq!(vec!["apple", "banana", "cat"], lines);
+    }
+
+    #[test]
+    fn sort_lines_by_length() {
+        let config = config(&["--sort-lines=line-length", "a", "poem.txt"]);
+        let mut lines = vec!["banana", "fig", "kiwi", "cat"];
+        sort_lines(&mut lines, config.sort_lines);
+        assert_eq!(vec!["fig", "cat", "kiwi", "banana"], lines);
+    }
+
+    #[test]
+    fn search_files_matches_sequential_search() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        for i in 0..20 {
+            let name = if i % 2 == 0 { format!("{}.txt", i) } else { format!("nested/{}.txt", i) };
+            let contents = format!("error {}\nok\nerror again\nignore error\n", i);
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        fs::write(dir.path().join("binary.bin"), b"error\0").unwrap();
+
+        let config = config(&["--and-not=ignore", "-r", "error", "."]);
+        let matcher = Matcher::new(&config).unwrap();
+        let paths = file::find_text_files(dir.path(), false).unwrap();
+        assert_eq!(20, paths.len());
+
+        let mut sequential = Vec::new();
+        for path in &paths {
+            let contents = file::read_file(path).unwrap();
+            sequential.extend(search_file(path, &contents, &matcher, false));
+        }
+
+        assert_eq!(40, sequential.len());
+        assert_eq!(sequential, search_files(&paths, &matcher));
+    }
+
+    #[test]
+    fn context_spans_only_on_matches() {
+        let contents = "\
+one
+Rust is rust
+two
+three
+four
+rust again";
+        let config = config(&["-C=1", "rust", "poem.txt"]);
+        let lines = search_context(&Matcher::new(&config).unwrap(), contents, 1, 1);
+
+        let nums: Vec<usize> = lines.iter().map(|l| l.line_num).collect();
+        assert_eq!(vec![1, 2, 3, 5, 6], nums);
+        for line in &lines {
+            assert_eq!(line.is_match, !line.spans.is_empty());
+        }
+        assert_eq!(vec![8..12], lines[1].spans);
+
+        let mut out = Vec::new();
+        output::write_context(&mut out, &lines, true).unwrap();
+        assert_eq!(
+            "1~one\n2:Rust is \x1b[1;31mrust\x1b[0m\n3~two\n--\n5~four\n6:\x1b[1;31mrust\x1b[0m again\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn case_insensitive_spans_map_to_original_bytes() {
+        let mut config = config(&["straße", "poem.txt"]);
+        config.case_sensitive = false;
+        let matcher = Matcher::new(&config).unwrap();
+        assert_eq!(vec![2..9], matcher.find_spans("\u{130}STRAßE"));
+    }
+
+    #[test]
+    fn paragraph_mode_prints_whole_paragraph() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prose.txt");
+        fs::write(&path, "\
+First paragraph,
+nothing here.
+
+Second paragraph
+mentions a needle
+and ends here.
+
+
+Third one.
+").unwrap();
+
+        let config = config(&["--paragraph", "needle", path.to_str().unwrap()]);
+        let mut out = Vec::new();
+        run_with_writer(config, &mut out).unwrap();
+        assert_eq!(
+            "Line 4:\nSecond paragraph\nmentions a needle\nand ends here.\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn split_paragraphs_tracks_start_lines() {
+        let starts: Vec<usize> = split_paragraphs("a\n\nb\nc\n \n\nd")
+            .iter()
+            .map(|p| p.start)
+            .collect();
+        assert_eq!(vec![1, 3, 7], starts);
+    }
+
+    #[test]
+    fn count_lists_every_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        fs::write(&a, "error one\nfine\nerror two\n").unwrap();
+        fs::write(&b, "all fine\n").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-c", "error", a, b]), &mut out).unwrap();
+        assert_eq!(format!("{}:2\n{}:0\n", a, b), String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-c", "--no-zero-counts", "error", a, b]), &mut out).unwrap();
+        assert_eq!(format!("{}:2\n", a), String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-c", "error", b]), &mut out).unwrap();
+        assert_eq!("0\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn search_bytes_matches_around_invalid_utf8() {
+        let contents = b"\xff\xfeneedle\xc3\n\x80plain\nNEEDLE\xff\n";
+
+        let literal = config(&["--byte-mode", "needle", "log.bin"]);
+        let found = search_bytes(&Matcher::new(&literal).unwrap(), contents);
+        assert_eq!(vec![(1, &b"\xff\xfeneedle\xc3"[..])], found);
+
+        let regex = config(&["--byte-mode", "-E", "(?i)need+le", "log.bin"]);
+        let found = search_bytes(&Matcher::new(&regex).unwrap(), contents);
+        let nums: Vec<usize> = found.iter().map(|(n, _)| *n).collect();
+        assert_eq!(vec![1, 3], nums);
+    }
+
+    #[test]
+    fn byte_mode_prints_lines_lossily() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mixed.log");
+        fs::write(&path, b"ok\n\xffneedle\xfe\n").unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--byte-mode", "needle", path.to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!("\u{fffd}needle\u{fffd}\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn lazy_discovery_matches_eager_search() {
+        let dir = tempfile::tempdir().unwrap();
+        for sub in ["a", "a/b", "c"] {
+            fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        for (i, name) in ["a.txt", "a/1.txt", "a/b/2.txt", "c/3.txt", "z.txt"].iter().enumerate() {
+            fs::write(dir.path().join(name), format!("needle {}\nhay\n", i)).unwrap();
+        }
+
+        let config = config(&["-r", "needle", "."]);
+        let matcher = Matcher::new(&config).unwrap();
+
+        let lazy: Vec<PathBuf> = file::walk_text_files(dir.path(), false).unwrap().collect();
+        let eager = file::find_text_files(dir.path(), false).unwrap();
+        assert_eq!(eager, lazy);
+
+        let mut streamed = Vec::new();
+        let cancel = AtomicBool::new(false);
+        search_files_streaming(lazy.into_iter(), &matcher, &cancel, |group| {
+            streamed.extend(group);
+            Ok(())
+        }).unwrap();
+        streamed.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(search_files(&eager, &matcher), streamed);
+    }
+
+    #[test]
+    fn line_ranges_restrict_search_to_their_union() {
+        let contents = (1..=30).map(|n| format!("line {}", n)).collect::<Vec<_>>().join("\n");
+        let config = config(&["--line-range=2:4,20:21,29:", "line", "poem.txt"]);
+        let nums: Vec<usize> = search_numbered(&Matcher::new(&config).unwrap(), &contents)
+            .iter()
+            .map(|(n, _)| *n)
+            .collect();
+        assert_eq!(vec![2, 3, 4, 20, 21, 29, 30], nums);
+    }
+
+    #[test]
+    fn line_ranges_are_validated() {
+        assert_eq!(Ok(vec![(1, Some(5)), (7, Some(7))]), parse_line_ranges(":5,7"));
+        assert!(parse_line_ranges("5:2").is_err());
+        assert!(parse_line_ranges("0:3").is_err());
+        assert!(parse_line_ranges("1:5,x").is_err());
+    }
+
+    #[test]
+    fn conflicting_options_are_rejected() {
+        let args = |args: &[&str]| {
+            ["minigrep"].iter().chain(args).map(|s| s.to_string()).collect::<Vec<_>>()
+        };
+
+        for bad in [
+            args(&["--count", "--passthru", "a", "f"]),
+            args(&["--paragraph", "-C=2", "a", "f"]),
+            args(&["-r", "a", "-"]),
+            args(&["-r", "--passthru", "a", "."]),
+            args(&["-r", "--paragraph", "a", "."]),
+        ] {
+            match Config::new(bad.into_iter()) {
+                Err(ConfigError::ConflictingOptions(_)) => {}
+                other => panic!("expected a conflict, got {:?}", other.err()),
+            }
+        }
+
+        assert!(Config::new(args(&["--count", "-r", "a", "."]).into_iter()).is_ok());
+    }
+
+    #[test]
+    fn builder_validates_options() {
+        let config = ConfigBuilder::new("needle", "log.txt")
+            .option("--count").unwrap()
+            .build()
+            .unwrap();
+        assert!(config.count);
+
+        let conflict = ConfigBuilder::new("needle", "log.txt")
+            .option("--byte-mode").unwrap()
+            .option("--passthru").unwrap()
+            .build();
+        assert_eq!(
+            Some(ConfigError::ConflictingOptions("--byte-mode can't be used with --passthru".to_string())),
+            conflict.err()
+        );
+    }
+
+    #[test]
+    fn options_conflict_by_the_groups_they_belong_to() {
+        let validate = |args: &[&str]| Config::new(["minigrep"].iter().chain(args).map(|s| s.to_string())).map(|_| ());
+        let conflict = |a: &str, b: &str| Err(ConfigError::ConflictingOptions(format!("{} can't be used with {}", a, b)));
+
+        assert_eq!(conflict("--count-replacements", "--nth"), validate(&["--replace=x", "--count-replacements", "--nth=2", "q", "f"]));
+        assert_eq!(conflict("--longest", "--histogram"), validate(&["--histogram", "--longest", "q", "f"]));
+        assert_eq!(conflict("--count", "--record-start"), validate(&["-c", "--record-start=>>", "q", "f"]));
+        assert_eq!(conflict("--repl", "--count-unique"), validate(&["--repl", "--count-unique", "q", "f"]));
+        assert_eq!(Ok(()), validate(&["--replace=x", "--count-replacements", "q", "f"]));
+        assert_eq!(Ok(()), validate(&["-r", "--stats", "--sort-by-relevance", "q", "."]));
+    }
+
+    #[test]
+    fn multiline_span_marks_every_covered_line() {
+        let contents = "\
+before
+fn main() {
+    body();
+}
+after
+tail";
+        let spanning = config(&["-E", "--multiline-span", "-C=1", r"fn main\(\) \{[^}]*\}", "f"]);
+        let lines = search_multiline(&Matcher::new(&spanning).unwrap(), contents, true, 1, 1);
+        let marked: Vec<(usize, bool)> = lines.iter().map(|l| (l.line_num, l.is_match)).collect();
+        assert_eq!(vec![(1, false), (2, true), (3, true), (4, true), (5, false)], marked);
+        assert_eq!(vec![0..11], lines[1].spans);
+        assert_eq!(vec![0..1], lines[3].spans);
+
+        let starting = config(&["-E", "-U", r"fn main\(\) \{[^}]*\}", "f"]);
+        let lines = search_multiline(&Matcher::new(&starting).unwrap(), contents, false, 0, 0);
+        let marked: Vec<(usize, bool)> = lines.iter().map(|l| (l.line_num, l.is_match)).collect();
+        assert_eq!(vec![(2, true)], marked);
+    }
+
+    #[test]
+    fn multiline_span_handles_single_line_matches() {
+        let config = config(&["-E", "--multiline-span", "bo.y", "f"]);
+        let lines = search_multiline(&Matcher::new(&config).unwrap(), "a\nbody\nc\n", true, 0, 0);
+        assert_eq!(1, lines.len());
+        assert_eq!(2, lines[0].line_num);
+        assert_eq!(vec![0..4], lines[0].spans);
+    }
+
+    #[test]
+    fn buffered_output_matches_unbuffered() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents: String = (0..2000).map(|n| format!("needle {}\nhay\n", n)).collect();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.path().join(name), &contents).unwrap();
+        }
+        let root = dir.path().to_str().unwrap();
+
+        let mut unbuffered = Vec::new();
+        run_with_writer(config(&["-r", "--sort=path", "needle", root]), &mut unbuffered).unwrap();
+
+        let mut buffered = BufWriter::new(Vec::new());
+        run_with_writer(config(&["-r", "--sort=path", "needle", root]), &mut buffered).unwrap();
+
+        assert_eq!(unbuffered, buffered.into_inner().unwrap());
+    }
+
+    #[test]
+    fn field_restricts_matching_to_one_column() {
+        let contents = "\
+alice,error,disk
+bob,ok,error
+carol,error
+dave";
+        let config = config(&["--field=2", "--delimiter=,", "error", "f.csv"]);
+        let matcher = Matcher::new(&config).unwrap();
+        assert_eq!(
+            vec!["alice,error,disk", "carol,error"],
+            search_with(&matcher, contents)
+        );
+        assert_eq!(vec![6..11], matcher.find_spans("alice,error,disk"));
+    }
+
+    #[test]
+    fn field_defaults_to_whitespace_splitting() {
+        assert_eq!(Some((7, "b")), field_of("  a    b c", 2, None));
+        assert_eq!(None, field_of("a b", 3, None));
+        assert_eq!(Some((2, "")), field_of("a,,c", 2, Some(',')));
+    }
+
+    #[test]
+    fn cancelling_stops_between_files_with_clean_output() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..50 {
+            fs::write(dir.path().join(format!("{:02}.txt", i)), "needle\nhay\nneedle\n").unwrap();
+        }
+        let config = config(&["-r", "needle", dir.path().to_str().unwrap()]);
+        let matcher = Matcher::new(&config).unwrap();
+        let paths = file::walk_text_files(dir.path(), false).unwrap();
+
+        let mut reported = 0;
+        let result = search_files_streaming(paths, &matcher, &config.cancel, |group| {
+            assert_eq!(2, group.len());
+            reported += 1;
+            config.cancel.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+
+        match result {
+            Err(Error::Cancelled { files_searched }) => assert!(reported <= files_searched),
+            other => panic!("expected cancellation, got {:?}", other),
+        }
+
+        let mut out = Vec::new();
+        let result = run_with_writer(config, &mut out);
+        assert!(matches!(result, Err(Error::Cancelled { files_searched: 0 })));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn max_runtime_stops_a_large_search_between_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..1000 {
+            fs::write(dir.path().join(format!("{:04}.txt", i)), "needle\n").unwrap();
+        }
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        let result = run_with_writer(config(&["-r", "--max-runtime=0", "needle", root]), &mut out);
+        match result {
+            Err(Error::TimedOut { files_searched }) => assert!(files_searched < 1000),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        for mode in ["--count", "--count-unique"] {
+            let result = run_with_writer(config(&["-r", mode, "--max-runtime=0", "needle", root]), &mut Vec::new());
+            assert!(matches!(result, Err(Error::TimedOut { .. })), "{}: {:?}", mode, result);
+        }
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--max-runtime=0", "--on-timeout=partial", "needle", root]), &mut out).unwrap();
+        assert!(out.len() < 1000 * "needle\n".len());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--max-runtime=60", "needle", root]), &mut out).unwrap();
+        assert_eq!(1000, String::from_utf8(out).unwrap().matches("needle").count());
+
+        let quiet = any_match(&config(&["-q", "-r", "--max-runtime=0", "needle", root]));
+        assert!(matches!(quiet, Err(Error::TimedOut { .. })), "{:?}", quiet);
+        let first = format!("{}/0000.txt", root);
+        assert!(!any_match(&config(&["-q", "--max-runtime=0", "--on-timeout=partial", "needle", &first])).unwrap());
+        assert!(any_match(&config(&["-q", "-r", "--max-runtime=60", "needle", root])).unwrap());
+
+        assert!(Config::new(["minigrep", "--on-timeout=partial", "q", "f"].iter().map(|s| s.to_string())).is_err());
+        assert!(Config::new(["minigrep", "--max-runtime=-1", "q", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn duplicate_patterns_compile_once() {
+        let config = config(&["-E", "--and-not=warn.*", "--and-not=err(or)?", "--and-not=warn.*", "err(or)?", "f"]);
+        let mut cache = RegexCache::new();
+        let matcher = Matcher::with_cache(&config, &mut cache).unwrap();
+        assert_eq!(2, cache.compiles());
+        assert!(!matcher.is_match("error"));
+
+        Matcher::with_cache(&config, &mut cache).unwrap();
+        assert_eq!(2, cache.compiles());
+    }
+
+    #[test]
+    fn only_count_files_counts_matching_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nneedle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay\n").unwrap();
+        fs::write(dir.path().join("sub/c.txt"), "hay\nneedle\n").unwrap();
+        fs::write(dir.path().join("sub/d.txt"), "").unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--only-count-files", "needle", dir.path().to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!("2 files matched\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn recursive_after_context_separates_groups_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\none\ntwo\nneedle\nthree\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "zero\nneedle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--sort=path", "-A=1", "-B=0", "needle", root]), &mut out).unwrap();
+        assert_eq!(
+            format!(
+                "File: {root}/a.txt\n1:needle\n2~one\n--\n4:needle\n5~three\n\nFile: {root}/b.txt\n2:needle\n",
+                root = root
+            ),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn contains_reports_presence() {
+        let contents = "Rust:\nsafe, fast, productive.\nTrust me.";
+        assert!(contains("fast", contents, true));
+        assert!(contains("RUST", contents, false));
+        assert!(!contains("RUST", contents, true));
+        assert!(!contains("slow", contents, false));
+
+        assert_eq!(Ok(true), contains_regex(r"^Tr\w+", contents, true));
+        assert_eq!(Ok(false), contains_regex(r"^tr\w+", contents, true));
+        assert_eq!(Ok(true), contains_regex(r"^tr\w+", contents, false));
+        assert!(contains_regex("(", contents, true).is_err());
+    }
+
+    #[test]
+    fn contains_stops_at_first_match() {
+        let mut scanned = 0;
+        let lines = ["one", "needle", "two", "needle"].into_iter().inspect(|_| scanned += 1);
+        assert!(lines_contain("needle", lines, true));
+        assert_eq!(2, scanned);
+    }
+
+    #[test]
+    fn max_line_bytes_skips_long_lines() {
+        let long = format!("needle{}", "x".repeat(10_000));
+        let contents = format!("needle one\n{}\nneedle two\n", long);
+
+        let limited = Matcher::new(&config(&["--max-line-bytes=100", "needle", "f"])).unwrap();
+        assert_eq!(vec!["needle one", "needle two"], search_with(&limited, &contents));
+        assert!(!limited.is_match_bytes(long.as_bytes()));
+
+        let unlimited = Matcher::new(&config(&["needle", "f"])).unwrap();
+        assert_eq!(3, search_with(&unlimited, &contents).len());
+    }
+
+    #[cfg(feature = "config-file")]
+    #[test]
+    fn rc_file_supplies_defaults_that_flags_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".minigreprc");
+        fs::write(&path, "ignore_case = true\ncontext = 3\nsort_lines = \"alpha\"\n").unwrap();
+
+        let args = |args: &[&str]| {
+            let mut defaults = Config::default();
+            rc::load(&path).unwrap().apply(&mut defaults).unwrap();
+            defaults.parse_args(std::iter::once("minigrep").chain(args.iter().copied()).map(String::from))
+                    .unwrap()
+        };
+
+        let config = args(&["needle", "f"]);
+        assert!(!config.case_sensitive);
+        assert_eq!((3, 3), (config.before_context, config.after_context));
+        assert_eq!(SortKey::Alpha, config.sort_lines);
+
+        let config = args(&["-C=1", "--sort-lines=line", "needle", "f"]);
+        assert_eq!((1, 1), (config.before_context, config.after_context));
+        assert_eq!(SortKey::Line, config.sort_lines);
+
+        fs::write(&path, "contxt = 3\n").unwrap();
+        let err = rc::load(&path).unwrap_err().to_string();
+        assert!(err.starts_with("Invalid config file"), "{}", err);
+        assert!(err.contains("contxt"), "{}", err);
+    }
+
+    #[test]
+    fn line_numbers_only_prints_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        fs::write(&a, "needle\nhay\nneedle again\n").unwrap();
+        fs::write(&b, "hay\nhay\nneedle\n").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--line-numbers-only", "needle", a]), &mut out).unwrap();
+        assert_eq!("1\n3\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--line-numbers-only", "needle", a, b]), &mut out).unwrap();
+        assert_eq!(format!("{a}:1\n{a}:3\n{b}:3\n", a = a, b = b), String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--line-numbers-only", "-r", "needle", dir.path().to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!(format!("{a}:1\n{a}:3\n{b}:3\n", a = a, b = b), String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn repl_runs_each_query_against_the_loaded_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, "Rust:\nsafe, fast, productive.\nPick three.\nTrust me.\n").unwrap();
+
+        let input = "fast\n(\nust\n:quit\nnever read\n".as_bytes();
+        let mut out = Vec::new();
+        repl(config(&["--repl", "-E", path.to_str().unwrap()]), input, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let mut prompts = out.split("> ").skip(1);
+        assert_eq!(Some("safe, fast, productive.\n"), prompts.next());
+        assert!(prompts.next().unwrap().starts_with("Invalid query: "));
+        assert_eq!(Some("Rust:\nTrust me.\n"), prompts.next());
+        assert_eq!(Some("\n"), prompts.next());
+        assert_eq!(None, prompts.next());
+    }
+
+    #[test]
+    fn line_length_bounds_pick_lines_with_or_without_a_query() {
+        let contents = "short\nerror: a much longer line\nerror\nmedium line\nnaïve\n";
+        let lines = |args: &[&str]| search_with(&Matcher::new(&config(args)).unwrap(), contents).join("|");
+
+        assert_eq!("short|error|naïve", lines(&["--max-line-length=5", "f"]));
+        assert_eq!("error: a much longer line|medium line", lines(&["--min-line-length=6", "f"]));
+        assert_eq!("short|error", lines(&["--max-line-length=5", "--line-length-bytes", "f"]));
+        assert_eq!("error", lines(&["--min-line-length=5", "--max-line-length=5", "error", "f"]));
+        assert_eq!("error: a much longer line", lines(&["--min-line-length=6", "error", "f"]));
+        assert_eq!(config(&["--min-line-length=80", "f"]).filenames, vec!["f"]);
+        assert!(Config::new(["minigrep", "--min-line-length=9", "--max-line-length=3", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn context_fill_gives_every_match_an_equal_window() {
+        let contents = "needle\n2\n3\n4\nneedle\n6\n7\n8\n9\nneedle\n";
+        let matcher = Matcher::new(&config(&["--context-fill=3", "needle", "f"])).unwrap();
+        let windows: Vec<Vec<usize>> = search_context_fill(&matcher, contents, 3).iter()
+                                                                                 .map(|window| window.iter().map(|l| l.line_num).collect())
+                                                                                 .collect();
+        assert_eq!(vec![vec![1, 2, 3], vec![4, 5, 6], vec![8, 9, 10]], windows);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("short.txt");
+        fs::write(&path, "needle\nhay\n").unwrap();
+        let mut out = Vec::new();
+        run_with_writer(config(&["--context-fill=4", "needle", path.to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!("1:needle\n2~hay\n~\n~\n", String::from_utf8(out).unwrap());
+        assert!(Config::new(["minigrep", "--context-fill=0", "needle", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn no_merge_context_keeps_each_match_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "a\nneedle 1\nb\nneedle 2\nc\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut merged = Vec::new();
+        run_with_writer(config(&["-C=1", "needle", path]), &mut merged).unwrap();
+        assert_eq!("1~a\n2:needle 1\n3~b\n4:needle 2\n5~c\n", String::from_utf8(merged).unwrap());
+
+        let mut separate = Vec::new();
+        run_with_writer(config(&["-C=1", "--no-merge-context", "needle", path]), &mut separate).unwrap();
+        assert_eq!(
+            "1~a\n2:needle 1\n3~b\n--\n3~b\n4:needle 2\n5~c\n",
+            String::from_utf8(separate).unwrap()
+        );
+    }
+
+    #[test]
+    fn path_format_controls_recursive_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a.txt"), "needle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let heading = |format: &[&str]| {
+            let mut args = vec!["-r"];
+            args.extend(format);
+            args.extend(["needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap().lines().next().unwrap().to_string()
+        };
+
+        assert_eq!(format!("File: {}/sub/a.txt", root), heading(&[]));
+        assert_eq!("File: sub/a.txt", heading(&["--path-format=relative"]));
+        let absolute = dir.path().canonicalize().unwrap().join("sub/a.txt");
+        assert_eq!(format!("File: {}", absolute.display()), heading(&["--path-format=absolute"]));
+
+        let config = config(&["--path-format=absolute", "-r", "needle", root]);
+        let missing = dir.path().join("gone.txt");
+        assert_eq!(missing, shown_path(&config, &missing));
+    }
+
+    #[test]
+    fn count_replacements_reports_counts_without_replacing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "cat and cat\ndog\ncat\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "dog\n").unwrap();
+        fs::write(dir.path().join("sub/c.txt"), "a catalog\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let a = dir.path().join("a.txt");
+        let a = a.to_str().unwrap();
+        let count = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.insert(args.len() - 1, "cat");
+            args.extend(["--replace=dog", "--count-replacements"]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let occurrences = fs::read_to_string(a).unwrap().matches("cat").count();
+        assert_eq!(format!("{} replacements\n", occurrences), count(&[a]));
+        assert_eq!(
+            format!("{root}/a.txt:3\n{root}/sub/c.txt:1\n4 replacements\n", root = root),
+            count(&["-r", root])
+        );
+        assert_eq!("cat and cat\ndog\ncat\n", fs::read_to_string(a).unwrap());
+        assert!(Config::new(["minigrep", "--count-replacements", "cat", a].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn longest_reports_the_longest_match_per_file_and_overall() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "an error\nno\nanother error\nyet another error\nerror\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "ünïcödé error\nfine\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "a long line without the word\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let a = dir.path().join("a.txt");
+        let run = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.insert(0, "--longest");
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("4 (17 chars): yet another error\n", run(&["error", a.to_str().unwrap()]));
+        assert_eq!(
+            format!("{0}/a.txt:4 (17 chars): yet another error\n{0}/b.txt:1 (13 chars): ünïcödé error\nLongest overall: {0}/a.txt:4 (17 chars)\n", root),
+            run(&["-r", "error", root])
+        );
+        assert_eq!(Some((1, 2, "ab")), longest_line([(1, "ab"), (2, "cd"), (3, "e")]));
+        assert_eq!(None, longest_line([]));
+    }
+
+    #[test]
+    fn replace_count_reports_replaced_occurrences() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        fs::write(&a, "cat and cat\ndog\ncat\n").unwrap();
+        fs::write(&b, "a cat\n").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--replace=dog", "--replace-count", "cat", a]), &mut out).unwrap();
+        assert_eq!("dog and dog\ndog\n3 replacements\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-E", "--replace=<$1>", "--replace-count", "c(a)t", a, b]), &mut out).unwrap();
+        assert_eq!(
+            format!("File: {a}\n<a> and <a>\n<a>\n\nFile: {b}\na <a>\n\n{a}:3\n{b}:1\n4 replacements\n", a = a, b = b),
+            String::from_utf8(out).unwrap()
+        );
+
+        let root = dir.path().to_str().unwrap();
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--sort=path", "--replace=dog", "--replace-count", "cat", root]), &mut out).unwrap();
+        assert_eq!(
+            format!("File: {a}\n1:dog and dog\n--\n3:dog\n\nFile: {b}\n1:a dog\n\n{a}:3\n{b}:1\n4 replacements\n", a = a, b = b),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn within_tag_only_matches_inside_the_tag() {
+        let contents = "\
+<log>
+  <entry>error outside</entry>
+  <message>error: disk full
+    error: retrying</message>
+  <message level=\"info\">ok</message>
+  <messages>error in a different tag</messages>
+</log>
+error after";
+        assert_eq!(
+            vec![false, false, true, true, true, false, false, false],
+            tag_lines(contents, "message")
+        );
+
+        let matcher = Matcher::new(&config(&["--within-tag=message", "error", "f.xml"])).unwrap();
+        assert_eq!(
+            vec!["  <message>error: disk full", "    error: retrying</message>"],
+            search_with(&matcher, contents)
+        );
+    }
+
+    #[test]
+    fn no_comments_skips_commented_occurrences() {
+        let contents = "\
+# retry the request
+retry(request)
+    # retry again
+    // retry once more
+let n = retry(); # retry";
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["retry", "f.py"]);
+            search_with(&Matcher::new(&config(&args)).unwrap(), contents)
+        };
+
+        assert_eq!(5, search(&[]).len());
+        assert_eq!(
+            vec!["retry(request)", "    // retry once more", "let n = retry(); # retry"],
+            search(&["--no-comments"])
+        );
+        assert_eq!(
+            vec!["# retry the request", "retry(request)", "    # retry again", "let n = retry(); # retry"],
+            search(&["--no-comments", "--comment-prefix=//"])
+        );
+        assert_eq!(Vec::<&str>::new(), search(&["--no-comments", "-v"]));
+        assert!(Config::new(["minigrep", "--comment-prefix=//", "q", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn section_start_only_matches_inside_sections() {
+        let contents = "\
+timeout = 1
+[server]
+timeout = 30
+port = 80
+[client]
+timeout = 5
+[server]
+timeout = 60";
+        let sections = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["timeout", "f.ini"]);
+            search_with(&Matcher::new(&config(&args)).unwrap(), contents)
+        };
+
+        assert_eq!(
+            vec!["timeout = 30", "timeout = 60"],
+            sections(&["--section-start=[server]", "--section-end=["])
+        );
+        assert_eq!(
+            vec!["timeout = 30", "timeout = 5", "timeout = 60"],
+            sections(&["--section-start=["])
+        );
+        assert_eq!(
+            vec!["timeout = 30", "timeout = 60"],
+            sections(&["-E", r"--section-start=^\[server\]$", r"--section-end=^\["])
+        );
+        assert!(Config::new(["minigrep", "--section-end=x", "q", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn file_match_renders_details_as_text_and_json() {
+        let found = FileMatch {
+            path: PathBuf::from("src/\"odd\".rs"),
+            line_num: 3,
+            line: "let x = \"needle\";".to_string(),
+            is_match: true,
+            column: Some(10),
+            byte_offset: Some(42),
+            match_spans: vec![4..5, 9..15],
+        };
+        assert_eq!("src/\"odd\".rs:3:10:let x = \"needle\";", found.to_string());
+
+        let json: serde_json::Value = serde_json::from_str(&found.to_json()).unwrap();
+        assert_eq!("src/\"odd\".rs", json["path"]);
+        assert_eq!(3, json["line_num"]);
+        assert_eq!("let x = \"needle\";", json["line"]);
+        assert_eq!(true, json["is_match"]);
+        assert_eq!(10, json["column"]);
+        assert_eq!(42, json["byte_offset"]);
+        assert_eq!(serde_json::json!([[4, 5], [9, 15]]), json["match_spans"]);
+
+        let plain = FileMatch { path: PathBuf::from("a.txt"), line_num: 1, line: "x".to_string(), ..FileMatch::default() };
+        assert_eq!("a.txt:1:x", plain.to_string());
+        let json: serde_json::Value = serde_json::from_str(&plain.to_json()).unwrap();
+        assert!(json["column"].is_null());
+
+        let contents = "hay\nsome needle needle\n";
+        let matcher = Matcher::new(&config(&["needle", "f"])).unwrap();
+        let detailed = search_file(Path::new("f"), contents, &matcher, true);
+        assert_eq!((Some(6), Some(4), vec![5..11, 12..18]), (detailed[0].column, detailed[0].byte_offset, detailed[0].match_spans.clone()));
+    }
+
+    #[test]
+    fn any_file_matches_stops_the_whole_search_at_the_first_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..2000).map(|i| dir.path().join(format!("{}.txt", i))).collect();
+        for path in &paths {
+            fs::write(path, "hay\nneedle\n").unwrap();
+        }
+        let matcher = Matcher::new(&config(&["needle", "f"])).unwrap();
+
+        let pulled = AtomicUsize::new(0);
+        let paths_pulled = paths.iter().cloned().inspect(|_| {
+            pulled.fetch_add(1, Ordering::SeqCst);
+        });
+        let (found, searched) = any_file_matches(paths_pulled, &matcher, 0, &AtomicBool::new(false)).unwrap();
+        assert!(found);
+        assert!(searched >= 1);
+        assert!(pulled.load(Ordering::SeqCst) < 500, "read {} files", pulled.load(Ordering::SeqCst));
+
+        let missing = Matcher::new(&config(&["absent", "f"])).unwrap();
+        assert_eq!((false, 20), any_file_matches(paths[..20].iter().cloned(), &missing, 0, &AtomicBool::new(false)).unwrap());
+
+        let interrupted = any_file_matches(paths.iter().cloned(), &matcher, 0, &AtomicBool::new(true));
+        assert!(matches!(interrupted, Err(Error::Cancelled { files_searched: 0 })), "{:?}", interrupted);
+    }
+
+    #[test]
+    fn json_output_has_versioned_matches_and_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hay\nsay \"needle\" needle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "nothing\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--json", "-r", "needle", root]), &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(JSON_VERSION, json["version"]);
+        assert_eq!(
+            serde_json::json!({"files_searched": 2, "files_matched": 1, "matches": 1}),
+            json["summary"]
+        );
+        assert_eq!(
+            serde_json::json!([{
+                "path": format!("{}/a.txt", root),
+                "line_num": 2,
+                "line": "say \"needle\" needle",
+                "is_match": true,
+                "column": 6,
+                "byte_offset": 4,
+                "match_spans": [[5, 11], [13, 19]],
+            }]),
+            json["matches"]
+        );
+    }
+
+    #[test]
+    fn hidden_files_are_only_searched_with_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".env"), "SECRET=needle\n").unwrap();
+        fs::create_dir(dir.path().join(".cache")).unwrap();
+        fs::write(dir.path().join(".cache/data.txt"), "needle\n").unwrap();
+        fs::write(dir.path().join("visible.txt"), "needle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let files = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--line-numbers-only", "-r", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(format!("{}/visible.txt:1\n", root), files(&[]));
+        assert_eq!(files(&[]), files(&["--hidden", "--no-hidden"]));
+        assert_eq!(
+            format!("{root}/.cache/data.txt:1\n{root}/.env:1\n{root}/visible.txt:1\n", root = root),
+            files(&["--hidden"])
+        );
+    }
+
+    #[test]
+    fn snippet_joins_groups_with_ellipses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "The tool reads\n  a file,\n\nthen a needle\nis found.\nMuch later\nanother needle\nends it.\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--snippet", "needle", path]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("\"then a needle ... another needle\"\n", search(&[]));
+        assert_eq!(
+            "\"a file, then a needle is found. Much later another needle ends it.\"\n",
+            search(&["-B=2", "-A=1"])
+        );
+        assert_eq!("\"then a needle ... Much later another needle\"\n", search(&["-B=1"]));
+        assert_eq!("", search(&["--and-not=needle"]));
+        assert_eq!("\"\"", output::snippet([(1, "  "), (3, "")]));
+    }
+
+    #[test]
+    fn sentence_context_prints_whole_sentences() {
+        let contents = "\
+It was a dark night. The wind
+howled through the trees, and the needle
+of the compass spun! Nobody moved.
+A needle without an end
+";
+        let matcher = Matcher::new(&config(&["needle", "f"])).unwrap();
+        assert_eq!(
+            vec![
+                "The wind\nhowled through the trees, and the needle\nof the compass spun!",
+                "A needle without an end",
+            ],
+            search_sentences(&matcher, contents)
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("story.txt");
+        fs::write(&path, contents).unwrap();
+        let mut out = Vec::new();
+        run_with_writer(config(&["--sentence-context", "needle", path.to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!(
+            "The wind howled through the trees, and the needle of the compass spun!\nA needle without an end\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn timing_lists_every_searched_file() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.path().join(name), "needle\n").unwrap();
+        }
+        fs::write(dir.path().join("d.txt"), "hay\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--timing", "-r", "needle", root]), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let (_, timings) = out.split_once("\n\nSlowest files:\n").unwrap();
+        let mut timed: Vec<&str> = timings.lines()
+                                          .map(|line| {
+                                              let (ms, path) = line.split_once("ms ").unwrap();
+                                              assert!(ms.parse::<f64>().is_ok(), "{}", line);
+                                              path
+                                          })
+                                          .collect();
+        timed.sort();
+        let expected: Vec<String> = ["a.txt", "b.txt", "c.txt", "d.txt"].iter()
+                                                                        .map(|name| format!("{}/{}", root, name))
+                                                                        .collect();
+        assert_eq!(expected, timed);
+    }
+
+    #[test]
+    fn literal_and_regex_patterns_match_together() {
+        let contents = "\
+price: $4.99
+order 1234 shipped
+nothing here
+price: $10 for order 56";
+        let mixed = config(&["-e=$4.99", "-E=order \\d+", "f.txt"]);
+        assert_eq!(vec!["f.txt"], mixed.filenames);
+
+        let matcher = Matcher::new(&mixed).unwrap();
+        assert_eq!(
+            vec!["price: $4.99", "order 1234 shipped", "price: $10 for order 56"],
+            search_with(&matcher, contents)
+        );
+        assert_eq!(vec![7..12], matcher.find_spans("price: $4.99"));
+        assert_eq!(vec![15..23], matcher.find_spans("price: $10 for order 56"));
+
+        let overlapping = Matcher::new(&config(&["-e=der 1", "-E=or\\w+", "f"])).unwrap();
+        assert_eq!(vec![0..7], overlapping.find_spans("order 1234"));
+    }
+
+    #[test]
+    fn replace_interactive_applies_only_accepted_replacements() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        let c = dir.path().join("c.txt");
+        fs::write(&a, "cat and cat\r\ndog\ncat\n").unwrap();
+        fs::write(&b, "no cat here\n").unwrap();
+        fs::write(&c, "cat\ncat\n").unwrap();
+        let names = [a.to_str().unwrap(), b.to_str().unwrap(), c.to_str().unwrap()];
+        let mut args = vec!["--replace=dog", "--replace-interactive", "cat"];
+        args.extend(names);
+
+        let mut out = Vec::new();
+        let replaced = replace_interactive(&config(&args), "n\ny\ny\nn\na\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(4, replaced);
+        assert_eq!("cat and dog\r\ndog\ndog\n", fs::read_to_string(&a).unwrap());
+        assert_eq!("no cat here\n", fs::read_to_string(&b).unwrap());
+        assert_eq!("dog\ndog\n", fs::read_to_string(&c).unwrap());
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&format!("{}:1\n- cat and cat\n+ dog and cat\nReplace? [y/n/a/q] ", names[0])), "{}", out);
+        assert!(out.contains("- cat and cat\n+ cat and dog\n"), "{}", out);
+        assert_eq!(5, out.matches("Replace?").count());
+
+        fs::write(&c, "cat\n").unwrap();
+        let quit = replace_interactive(&config(&["--replace=x", "--replace-interactive", "cat", names[2]]), "q\n".as_bytes(), &mut Vec::new());
+        assert_eq!(0, quit.unwrap());
+        assert_eq!("cat\n", fs::read_to_string(&c).unwrap());
+
+        fs::write(&c, "\u{feff}cat\n").unwrap();
+        replace_interactive(&config(&["--replace=dog", "--replace-interactive", "cat", names[2]]), "y\n".as_bytes(), &mut Vec::new()).unwrap();
+        assert_eq!("\u{feff}dog\n", fs::read_to_string(&c).unwrap());
+    }
+
+    #[test]
+    fn replace_interactive_refuses_input_it_cant_write_back() {
+        let refused = |args: &[&str]| {
+            let args = ["minigrep", "--replace=dog", "--replace-interactive", "cat"].iter().chain(args);
+            Config::new(args.map(|s| s.to_string())).is_err()
+        };
+        assert!(refused(&["--try-encodings=latin1", "a.txt"]));
+        assert_eq!(cfg!(feature = "bzip2"), refused(&["a.txt.bz2"]));
+        assert!(!refused(&["a.txt"]));
+    }
+
+    #[test]
+    fn expand_env_substitutes_variables_in_the_query() {
+        env::set_var("MINIGREP_TEST_USER", "alice");
+        env::remove_var("MINIGREP_TEST_UNSET");
+
+        let expanded = config(&["--expand-env", "${MINIGREP_TEST_USER} logged in${MINIGREP_TEST_UNSET}", "auth.log"]);
+        assert_eq!("alice logged in", expanded.query);
+        let matcher = Matcher::new(&expanded).unwrap();
+        assert_eq!(vec!["alice logged in"], search_with(&matcher, "bob logged in\nalice logged in\n"));
+
+        assert_eq!("${MINIGREP_TEST_USER}", config(&["${MINIGREP_TEST_USER}", "f"]).query);
+        assert_eq!("a ${unclosed", expand_env("a ${unclosed", UndefinedVar::Error).unwrap());
+
+        let strict = Config::new(
+            ["minigrep", "--expand-env=error", "${MINIGREP_TEST_UNSET}", "f"].iter().map(|s| s.to_string())
+        );
+        assert_eq!(Some(ConfigError::UndefinedVariable("MINIGREP_TEST_UNSET".to_string())), strict.err());
+    }
+
+    #[test]
+    fn summary_line_is_a_single_line_of_totals() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nhay\nneedle\n").unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "needle\n").unwrap();
+        for i in 0..8 {
+            fs::write(dir.path().join(format!("hay{}.txt", i)), "hay\n").unwrap();
+        }
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--summary-line", "-r", "needle", root]), &mut out).unwrap();
+        assert_eq!("3 matches in 2 files (scanned 10 files)\n", String::from_utf8(out).unwrap());
+
+        let b = dir.path().join("sub/b.txt");
+        let mut out = Vec::new();
+        run_with_writer(config(&["--summary-line", "needle", b.to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!("1 match in 1 file (scanned 1 file)\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn wrap_indents_continuation_rows_under_the_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "short\nthe needle is in this rather long line\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--wrap=16", "-C=1", "needle", path]), &mut out).unwrap();
+        assert_eq!(
+            "1~short\n2:the needle is \n  in this rather\n   long line\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--wrap", "-C=1", "needle", path]), &mut out).unwrap();
+        assert_eq!("1~short\n2:the needle is in this rather long line\n", String::from_utf8(out).unwrap());
+
+        assert_eq!("ab\x1b[1;31mc\x1b[0m\n   de", output::wrap("ab\x1b[1;31mc\x1b[0mde", 3, 6));
+    }
+
+    #[test]
+    fn count_unique_counts_distinct_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.log");
+        let b = dir.path().join("b.log");
+        fs::write(&a, "error: disk full\nok\nerror: disk full\nerror: disk full\n").unwrap();
+        fs::write(&b, "error: disk full\nerror: timeout\n").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--count-unique", "error", a]), &mut out).unwrap();
+        assert_eq!("1\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--count-unique", "error", a, b]), &mut out).unwrap();
+        assert_eq!(format!("{}:1\n{}:2\nTotal: 2\n", a, b), String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn only_matching_prints_each_match_on_a_numbered_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "no match\nfoo=1 bar=22\nbaz=333\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-o", "-E", r"\w+=\d+", path]), &mut out).unwrap();
+        assert_eq!("2:foo=1\n2:bar=22\n3:baz=333\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-o", "--column", "-E", "ba|bar", path]), &mut out).unwrap();
+        assert_eq!("2:7:ba\n3:1:ba\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn overlapping_reports_matches_that_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "aaaa\nbanana\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.push(path);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("1:aa\n1:aa\n", search(&["-o", "aa"]));
+        assert_eq!("1:1:aa\n1:2:aa\n1:3:aa\n", search(&["-o", "--column", "--overlapping", "aa"]));
+        assert_eq!("2:2:ana\n", search(&["-o", "--column", "ana"]));
+        assert_eq!("2:2:ana\n2:4:ana\n", search(&["-o", "--column", "--overlapping", "-E", "an."]));
+        assert!(Config::new(["minigrep", "--overlapping", "aa", path].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn max_matches_per_line_summarizes_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "x1 x2 x3 x4 x5 x6 x7 x8 x9 x10\nx1 x2\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-o", "--max-matches-per-line=3", "-E", r"x\d+", path]), &mut out).unwrap();
+        assert_eq!("1:x1\n1:x2\n1:x3\n1:(+7 more)\n2:x1\n2:x2\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-o", "--max-matches-per-line=0", "x1", path]), &mut out).unwrap();
+        assert_eq!("1:(+2 more)\n2:(+1 more)\n", String::from_utf8(out).unwrap());
+
+        let args = ["minigrep", "--max-matches-per-line=3", "x", path];
+        assert!(Config::new(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    fn script_decides_which_lines_match() {
+        let contents = "error\nerror: disk is full\nwarning: disk is nearly full\nERROR: timeout\n";
+        let scripted = config(&[r#"--script=line.len() > 10 && line.contains("error")"#, "f.log"]);
+        assert_eq!(vec!["f.log"], scripted.filenames);
+        let matcher = Matcher::new(&scripted).unwrap();
+        assert_eq!(vec!["error: disk is full"], search_with(&matcher, contents));
+
+        let folded = config(&[r#"--script=line.to_lower().starts_with("error") && !(line == "error")"#, "f"]);
+        assert_eq!(
+            vec!["error: disk is full", "ERROR: timeout"],
+            search_with(&Matcher::new(&folded).unwrap(), contents)
+        );
+
+        for (script, problem) in [
+            ("line.len()", "must be a boolean"),
+            ("line.len() > \"10\"", "comparing an integer"),
+            ("line.contains(1)", "needs a string"),
+            ("line.open(\"/etc/passwd\")", "unknown method `open`"),
+            ("(line == \"x\"", "expected `)`"),
+        ] {
+            let err = Matcher::new(&config(&[&format!("--script={}", script), "f"])).err().unwrap().to_string();
+            assert!(err.starts_with("Invalid --script: ") && err.contains(problem), "{}: {}", script, err);
+        }
+
+        let deep = [
+            format!("{}true{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("{}true", "!".repeat(100_000)),
+            format!("{}1 > 0", "-".repeat(100_000)),
+            vec!["true"; 1000].join(" && "),
+        ];
+        for script in deep {
+            let err = script::Script::new(&script).unwrap_err();
+            assert!(err.contains("nests more than"), "{}", err);
+        }
+        assert!(script::Script::new(&format!("{}true{}", "(".repeat(50), ")".repeat(50))).is_ok());
+
+        let script = script::Script::new(r#"line.contains("error")"#).unwrap();
+        assert!(script.is_match_within("an error", Duration::from_secs(60)));
+        assert!(!script.is_match_within("an error", Duration::ZERO));
+    }
+
+    #[test]
+    fn record_lines_bracket_each_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "a\nneedle 1\nb\nc\nd\nneedle 2\nneedle 3\n").unwrap();
+        let path = path.to_str().unwrap();
+        let records = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--record-start=<<", "--record-end=>>", "needle", path]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("<<\nneedle 1\n>>\n<<\nneedle 2\n>>\n<<\nneedle 3\n>>\n", records(&[]));
+        assert_eq!(
+            "<<\n1~a\n2:needle 1\n3~b\n>>\n<<\n5~d\n6:needle 2\n7:needle 3\n>>\n",
+            records(&["-C=1"])
+        );
+        assert_eq!(
+            "<<\n1~a\n2:needle 1\n3~b\n>>\n<<\n5~d\n6:needle 2\n7:needle 3\n>>\n<<\n6:needle 2\n7:needle 3\n>>\n",
+            records(&["-C=1", "--no-merge-context"])
+        );
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn cache_reuses_results_for_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nhay\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "more needle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let cache = format!("--cache-dir={}", cache_dir.path().display());
+        // How many files were read and searched, counted by `Timings`, and
+        // what was found.
+        let search = |query: &str| {
+            let config = config(&["-r", &cache, query, root]);
+            let matcher = Matcher::new(&config).unwrap();
+            let timings = Timings::new(false, false);
+            let mut found = Vec::new();
+            stream_tree(&config, &matcher, &timings, OnUnreadable::Skip, walk_roots(&config).unwrap(), |group| {
+                found.extend(group);
+                Ok(())
+            }).unwrap();
+            found.sort_by(|a, b| (&a.path, a.line_num).cmp(&(&b.path, b.line_num)));
+            (timings.searched.load(Ordering::SeqCst), found)
+        };
+
+        let (read, first) = search("needle");
+        assert_eq!((3, 2), (read, first.len()));
+        let (read, second) = search("needle");
+        assert_eq!((0, &first), (read, &second));
+
+        fs::write(dir.path().join("b.txt"), "needle in the hay\n").unwrap();
+        let (read, third) = search("needle");
+        assert_eq!((1, 3), (read, third.len()));
+
+        // Another query is another search, with nothing cached yet.
+        let (read, _) = search("hay");
+        assert_eq!(3, read);
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn git_rev_searches_the_committed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/f.txt"), "old needle\nhay\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("src/f.txt")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "add f", &tree, &[]).unwrap();
+        fs::write(dir.path().join("src/f.txt"), "new needle\n").unwrap();
+
+        let search = |rev: &str, path: &Path| {
+            let mut out = Vec::new();
+            run_with_writer(config(&[&format!("--git-rev={}", rev), "needle", path.to_str().unwrap()]), &mut out)
+                .map(|_| String::from_utf8(out).unwrap())
+                .map_err(|err| err.to_string())
+        };
+
+        let path = dir.path().join("src/f.txt");
+        assert_eq!(Ok("old needle\n".to_string()), search("HEAD", &path));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Ok("old needle\n".to_string()), search("HEAD", &path));
+
+        let missing = dir.path().join("src/nope.txt");
+        assert_eq!(Err(format!("{} doesn't exist at HEAD", missing.display())), search("HEAD", &missing));
+        assert!(search("no-such-rev", &path).unwrap_err().starts_with("Unknown revision no-such-rev: "));
+    }
+
+    #[test]
+    fn invert_match_swaps_matches_and_context() {
+        let contents = "needle 1\nneedle 2\nhay\nneedle 3\nneedle 4\nneedle 5\nstraw\n";
+        let matcher = Matcher::new(&config(&["-v", "needle", "f"])).unwrap();
+        assert_eq!(vec!["hay", "straw"], search_with(&matcher, contents));
+
+        let lines = search_context(&matcher, contents, 1, 1);
+        let flags: Vec<(usize, bool)> = lines.iter().map(|l| (l.line_num, l.is_match)).collect();
+        assert_eq!(vec![(2, false), (3, true), (4, false), (6, false), (7, true)], flags);
+        assert!(lines.iter().all(|l| l.spans.is_empty()));
+
+        let mut out = Vec::new();
+        output::write_context(&mut out, &lines, false).unwrap();
+        assert_eq!("2~needle 2\n3:hay\n4~needle 3\n--\n6~needle 5\n7:straw\n", String::from_utf8(out).unwrap());
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn try_encodings_uses_the_first_that_decodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let latin1 = dir.path().join("latin1.txt");
+        fs::write(&latin1, b"caf\xe9 needle\nhay\n").unwrap();
+        let latin1 = latin1.to_str().unwrap();
+        let search = |encodings: &str| {
+            let mut out = Vec::new();
+            run_with_writer(config(&[&format!("--try-encodings={}", encodings), "needle", latin1]), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("café needle\n", search("utf-8,latin1"));
+        assert_eq!("caf\u{fffd} needle\n", search("utf-8,utf-8"));
+        let unknown = Config::new(["minigrep", "--try-encodings=utf-8,klingon", "needle", latin1].iter().map(|s| s.to_string()));
+        assert_eq!(Some(ConfigError::Invalid("--try-encodings has an unknown encoding")), unknown.err());
+    }
+
+    #[test]
+    fn histogram_buckets_matches_by_line_range() {
+        let lines_at = |total: usize, bucket: usize| {
+            histogram(&[1, 2, 3, 15, 95, 100], total, bucket).into_iter()
+                                                            .map(|(range, n)| (*range.start(), *range.end(), n))
+                                                            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![(1, 10, 3), (11, 20, 1), (21, 30, 0), (31, 40, 0), (41, 50, 0), (51, 60, 0), (61, 70, 0), (71, 80, 0), (81, 90, 0), (91, 100, 2)],
+            lines_at(100, 10)
+        );
+        assert_eq!(vec![(1, 1, 1), (2, 2, 1), (3, 3, 1)], lines_at(3, 10));
+        assert_eq!(vec![(1, 9, 3), (10, 18, 1)], lines_at(18, 2));
+        assert!(lines_at(0, 10).is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "needle\nneedle\nhay\nhay\nhay\nneedle\nhay\nhay\nhay\nhay\nhay\nhay\n").unwrap();
+        let mut out = Vec::new();
+        run_with_writer(config(&["--histogram", "needle", path.to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!(
+            "  1-2: ##  (2)\n  3-4:     (0)\n  5-6: #   (1)\n  7-8:     (0)\n 9-10:     (0)\n11-12:     (0)\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn literal_replace_keeps_the_surrounding_text() {
+        let matcher = Matcher::new(&config(&["a.b", "f"])).unwrap();
+        assert_eq!(("x$1x and axb".to_string(), 1), matcher.replace("a.b and axb", "x$1x"));
+
+        let mut folded = config(&["straße", "f"]);
+        folded.case_sensitive = false;
+        let matcher = Matcher::new(&folded).unwrap();
+        assert_eq!(
+            ("Road [road] and [road].".to_string(), 2),
+            matcher.replace("Road [STRAßE] and [Straße].", "road")
+        );
+    }
+
+    #[test]
+    fn regex_replace_changes_the_case_of_groups() {
+        let matcher = Matcher::new(&config(&["-E", r"(\w+)_(\w+)", "f"])).unwrap();
+        let replace = |replacement| matcher.replace("user_name = first_NAME;", replacement).0;
+
+        assert_eq!("USER_name = FIRST_NAME;", replace(r"\U$1\E_$2"));
+        assert_eq!("userName = firstNAME;", replace(r"$1\u$2"));
+        assert_eq!("userName = firstName;", replace(r"$1\u\L$2"));
+        assert_eq!("User-NAME = First-NAME;", replace(r"\u$1-\U$2"));
+        assert_eq!("user_name = first_NAME;", replace(r"${1}_$2\E"));
+
+        let bytes = Matcher::new(&config(&["-E", "--no-unicode", r"(\w+)_(\w+)", "f"])).unwrap();
+        assert_eq!("USER = FIRST;", bytes.replace("user_name = first_NAME;", r"\U$1").0);
+    }
+
+    #[test]
+    fn context_header_gives_each_group_its_line_range() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("f.txt"), "a\nneedle 1\nb\nc\nd\ne\nneedle 2\n").unwrap();
+        let path = dir.path().join("f.txt");
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut out = Vec::new();
+            run_with_writer(config(args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let single = search(&["--context-header", "-C=1", "needle", path]);
+        assert_eq!("@@ lines 1-3 @@\n1~a\n2:needle 1\n3~b\n@@ lines 6-7 @@\n6~e\n7:needle 2\n", single);
+
+        let recursive = search(&["--context-header", "-C=1", "-r", "needle", dir.path().to_str().unwrap()]);
+        assert_eq!(format!("File: {}\n{}", path, single), recursive);
+
+        // Without context, each matching line is a group of its own.
+        assert_eq!("@@ lines 2-2 @@\nneedle 1\n@@ lines 7-7 @@\nneedle 2\n", search(&["--context-header", "needle", path]));
+    }
+
+    #[test]
+    fn search_stream_searches_any_reader() {
+        let data = "hay\nneedle one\r\nhay\nhay\nanother needle\n";
+
+        let results = search_stream(io::Cursor::new(data.as_bytes()), &config(&["needle", "-"])).unwrap();
+        let found: Vec<_> = results.matches()
+                                   .map(|m| (m.line_num, m.line.as_str(), m.column, m.byte_offset))
+                                   .collect();
+        assert_eq!(vec![(2, "needle one", Some(1), Some(4)), (5, "another needle", Some(9), Some(24))], found);
+        assert!(results.lines.iter().all(|m| m.path == Path::new("-")));
+
+        let results = search_stream(io::Cursor::new(data.as_bytes()), &config(&["-A=1", "needle", "-"])).unwrap();
+        let lines: Vec<(usize, bool)> = results.lines.iter().map(|m| (m.line_num, m.is_match)).collect();
+        assert_eq!(vec![(2, true), (3, false), (5, true)], lines);
+        assert_eq!(2, results.matches().count());
+    }
+
+    #[test]
+    fn trim_strips_leading_whitespace_from_printed_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.rs");
+        fs::write(&path, "fn main() {\n        let needle = 1;\n    }\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--trim", "needle", path]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("let needle = 1;\n", search(&[]));
+        assert_eq!("let \x1b[1;31mneedle\x1b[0m = 1;\n", search(&["--color=always"]));
+        assert_eq!("1~fn main() {\n2:let needle = 1;\n3~}\n", search(&["-C=1"]));
+        assert_eq!("2:13:needle\n", search(&["-o", "--column"]));
+    }
+
+    #[test]
+    fn search_and_report_tells_no_matches_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hay\nneedle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let b = dir.path().join("b.txt");
+
+        match search_and_report(&config(&["-r", "-B=1", "needle", root])).unwrap() {
+            Outcome::Matches(results) => {
+                assert!(!results.is_empty() && results.matched());
+                let lines: Vec<(&str, bool)> = results.lines.iter().map(|m| (m.line.as_str(), m.is_match)).collect();
+                assert_eq!(vec![("hay", false), ("needle", true)], lines);
+            }
+            Outcome::NoMatches => panic!("expected matches"),
+        }
+        assert_eq!(Outcome::NoMatches, search_and_report(&config(&["needle", b.to_str().unwrap()])).unwrap());
+        assert!(SearchResults::default().is_empty() && !SearchResults::default().matched());
+    }
+
+    #[test]
+    fn all_patterns_only_matches_files_with_every_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "uses alpha\nand beta\nnothing\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "only alpha\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "only beta\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-r", "--sort=path", "-e=alpha", "-e=beta", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(3, search(&[]).matches("File: ").count());
+        assert_eq!(format!("File: {}/a.txt\n1:uses alpha\n2:and beta\n", root), search(&["--all-patterns"]));
+        assert_eq!("1 files matched\n", search(&["--all-patterns", "--only-count-files"]));
+        assert!(Config::new(["minigrep", "--all-patterns", "alpha", root].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn show_context_markers_numbers_lines_without_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "needle\nhay\nneedle again\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["needle", path]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("needle\nneedle again\n", search(&[]));
+        assert_eq!("1:needle\n--\n3:needle again\n", search(&["--show-context-markers"]));
+        assert_eq!("1:needle\n2~hay\n3:needle again\n", search(&["--show-context-markers", "-A=1"]));
+        assert_eq!("3:needle again\n", search(&["--show-context-markers", "--nth=2"]));
+    }
+
+    #[test]
+    fn preserve_case_follows_the_case_of_each_match() {
+        let mut folded = config(&["--replace=colour", "--preserve-case", "color", "f.txt"]);
+        folded.case_sensitive = false;
+        let matcher = Matcher::new(&folded).unwrap();
+
+        assert_eq!(("Colour".to_string(), 1), matcher.replace("Color", "colour"));
+        assert_eq!(("COLOUR".to_string(), 1), matcher.replace("COLOR", "colour"));
+        assert_eq!(("colour".to_string(), 1), matcher.replace("color", "colour"));
+        assert_eq!(
+            ("Colour, COLOUR and colour, but colour".to_string(), 4),
+            matcher.replace("Color, COLOR and color, but cOLoR", "colour")
+        );
+        assert_eq!("Hi", matcher::match_case("X", "hI"));
+        assert!(Config::new(["minigrep", "-E", "--replace=x", "--preserve-case", "q", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn total_only_sums_the_per_file_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nhay\nneedle needle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay\n").unwrap();
+        fs::write(dir.path().join("sub/c.txt"), "needle\nneedle\nneedle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let count = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-c", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let per_file: usize = count(&["-r"]).lines().map(|line| line.rsplit(':').next().unwrap().parse::<usize>().unwrap()).sum();
+        assert_eq!(5, per_file);
+        assert_eq!(format!("{}\n", per_file), count(&["-r", "--total-only"]));
+        assert_eq!("3\n", count(&["-r", "--total-only", "--min-matches=3"]));
+        assert!(Config::new(["minigrep", "--total-only", "needle", root].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn print_empty_lists_files_without_matches_in_path_order() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay\n").unwrap();
+        fs::write(dir.path().join("sub/c.txt"), "needle\n").unwrap();
+        fs::write(dir.path().join("sub/d.txt"), "straw\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let run = |args: &[&str]| {
+            let mut out = Vec::new();
+            run_with_writer(config(args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            format!(
+                "File: {0}/a.txt\n1:needle\n\n{0}/b.txt: (no matches)\n\nFile: {0}/sub/c.txt\n1:needle\n\n{0}/sub/d.txt: (no matches)\n",
+                root
+            ),
+            run(&["-r", "--print-empty", "needle", root])
+        );
+
+        let (a, b) = (format!("{}/a.txt", root), format!("{}/b.txt", root));
+        assert_eq!(format!("{}: (no matches)\n\nFile: {}\nneedle\n", b, a), run(&["--print-empty", "needle", &b, &a]));
+        assert!(Config::new(["minigrep", "--print-empty", "needle", &a].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn compact_headings_leave_out_the_blank_lines_between_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.path().join(name), "needle\nhay\n").unwrap();
+        }
+        let root = dir.path().to_str().unwrap();
+        let run = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-r", "--sort=path", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            format!("File: {0}/a.txt\n1:needle\n\nFile: {0}/b.txt\n1:needle\n\nFile: {0}/c.txt\n1:needle\n", root),
+            run(&[])
+        );
+        assert_eq!(format!("{0}/a.txt:\n1:needle\n{0}/b.txt:\n1:needle\n{0}/c.txt:\n1:needle\n", root), run(&["--compact"]));
+        assert!(Config::new(["minigrep", "--compact", "needle", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn zero_based_counts_lines_and_columns_from_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "needle\nhay\nhay needle\n").unwrap();
+        let file = path.to_str().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let run = |args: &[&str]| {
+            let mut out = Vec::new();
+            run_with_writer(config(args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("2~hay\n3:hay needle\n", run(&["-B=1", "hay needle", file]));
+        assert_eq!("1~hay\n2:hay needle\n", run(&["--zero-based", "-B=1", "hay needle", file]));
+        assert_eq!("0:0:needle\n2:4:needle\n", run(&["--zero-based", "-o", "--column", "needle", file]));
+        assert_eq!(
+            format!("File: {}/a.txt\n0:needle\n--\n2:hay needle\n", root),
+            run(&["--zero-based", "-r", "--show-context-markers", "needle", root])
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&run(&["--zero-based", "--json", "needle", file])).unwrap();
+        let found: Vec<(u64, u64)> = json["matches"].as_array()
+                                                    .unwrap()
+                                                    .iter()
+                                                    .map(|m| (m["line_num"].as_u64().unwrap(), m["column"].as_u64().unwrap()))
+                                                    .collect();
+        assert_eq!(vec![(0, 0), (2, 4)], found);
+    }
+
+    #[test]
+    fn output_file_saves_results_and_leaves_the_input_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.txt");
+        let saved = dir.path().join("out.txt");
+        fs::write(&input, "cat\ndog\ncat and cat\n").unwrap();
+        let (input, saved) = (input.to_str().unwrap(), saved.to_str().unwrap());
+        let output_file = format!("--output-file={}", saved);
+
+        run(config(&[&output_file, "cat", input])).unwrap();
+        assert_eq!("cat\ncat and cat\n", fs::read_to_string(saved).unwrap());
+
+        run(config(&[&output_file, "--replace=cow", "cat", input])).unwrap();
+        assert_eq!("cow\ncow and cow\n", fs::read_to_string(saved).unwrap());
+        assert_eq!("cat\ndog\ncat and cat\n", fs::read_to_string(input).unwrap());
+
+        let into_input = format!("--output-file={}", input);
+        assert!(matches!(run(config(&[&into_input, "cat", input])), Err(Error::Config(_))));
+        assert!(matches!(run(config(&[&output_file, "-r", "cat", dir.path().to_str().unwrap()])), Err(Error::Config(_))));
+        assert_eq!("cat\ndog\ncat and cat\n", fs::read_to_string(input).unwrap());
+
+        run(config(&[&into_input, "--force", "cat", input])).unwrap();
+        assert_eq!("cat\ncat and cat\n", fs::read_to_string(input).unwrap());
+        assert!(Config::new(["minigrep", "--force", "cat", input].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn annotate_marks_matches_with_carets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "let x = foo(bar);\nnothing\nfoo and foo\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--annotate", "foo", path]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("let x = foo(bar);\n        ^^^\nfoo and foo\n^^^     ^^^\n", search(&[]));
+        assert_eq!(
+            "1:let x = foo(bar);\n          ^^^\n2~nothing\n3:foo and foo\n  ^^^     ^^^\n",
+            search(&["-A=1"])
+        );
+        assert_eq!("\t   ^^^^", output::carets("\tab 日本 x", &[4..7, 7..10]));
+    }
+
+    #[test]
+    fn delete_removes_matches_and_drops_emptied_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "x=1 [noise]\n[noise]\nquiet\n[noise][NOISE] y=2\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.push(path);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("x=1 \n[NOISE] y=2\n", search(&["--delete", "[noise]"]));
+        assert_eq!("x=1 \n\n[NOISE] y=2\n", search(&["--delete", "--keep-empty", "[noise]"]));
+        assert_eq!("x= \n y=\n", search(&["--delete", "-E", r"\[\w+\]|\d"]));
+        assert!(Config::new(["minigrep", "--delete", "--replace=x", "q", "f"].iter().map(|s| s.to_string())).is_err());
+        assert!(Config::new(["minigrep", "--keep-empty", "q", "f"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn auto_color_respects_no_color_and_dumb_terminals() {
+        let resolve = |choice: ColorChoice, is_terminal, no_color: Option<&str>, term: Option<&str>| {
+            choice.resolve_with(is_terminal, no_color.map(OsStr::new), term.map(OsStr::new))
+        };
+
+        assert_eq!(ColorChoice::Always, resolve(ColorChoice::Auto, true, None, Some("xterm-256color")));
+        assert_eq!(ColorChoice::Always, resolve(ColorChoice::Auto, true, Some(""), None));
+        assert_eq!(ColorChoice::Never, resolve(ColorChoice::Auto, false, None, Some("xterm")));
+        assert_eq!(ColorChoice::Never, resolve(ColorChoice::Auto, true, Some("1"), Some("xterm")));
+        assert_eq!(ColorChoice::Never, resolve(ColorChoice::Auto, true, None, Some("dumb")));
+        assert_eq!(ColorChoice::Always, resolve(ColorChoice::Always, true, Some("1"), Some("dumb")));
+        assert_eq!(ColorChoice::Never, resolve(ColorChoice::Never, true, None, None));
+    }
+
+    #[test]
+    fn open_source_sorts_inputs_by_kind() {
+        use file::Source;
+
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("a.txt");
+        let compressed = dir.path().join("b.txt.bz2");
+        fs::write(&text, "needle\n").unwrap();
+
+        assert_eq!(Source::Stdin, file::open_source(Path::new("-")));
+        assert_eq!(Source::Dir(dir.path().to_path_buf()), file::open_source(dir.path()));
+        assert_eq!(Source::File(text.clone()), file::open_source(&text));
+        assert_eq!(Source::File(dir.path().join("missing")), file::open_source(&dir.path().join("missing")));
+        if cfg!(feature = "bzip2") {
+            assert_eq!(Source::Archive(compressed.clone()), file::open_source(&compressed));
+        } else {
+            assert_eq!(Source::File(compressed.clone()), file::open_source(&compressed));
+        }
+
+        assert_eq!("needle\n", file::open_source(&text).read(None).unwrap());
+        let err = file::open_source(dir.path()).read(None).unwrap_err();
+        assert_eq!(io::ErrorKind::IsADirectory, err.kind());
+    }
+
+    #[test]
+    fn search_and_report_lists_skipped_files_as_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\n").unwrap();
+        fs::write(dir.path().join("b.bin"), b"needle\0\n").unwrap();
+        fs::write(dir.path().join("c.txt"), b"needle \xff\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let results = match search_and_report(&config(&["-r", "needle", root])).unwrap() {
+            Outcome::Matches(results) => results,
+            Outcome::NoMatches => panic!("expected matches"),
+        };
+        assert_eq!(1, results.lines.len());
+        assert_eq!(2, results.warnings.len());
+        assert_eq!(Warning::Binary(dir.path().join("b.bin")), results.warnings[0]);
+        assert!(matches!(&results.warnings[1], Warning::Unreadable(path, _) if *path == dir.path().join("c.txt")));
+        assert_eq!(format!("{}/b.bin: skipped binary file", root), results.warnings[0].to_string());
+    }
+
+    #[test]
+    fn byte_order_mark_is_stripped_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bom.txt");
+        fs::write(&path, "\u{feff}needle first\nneedle second\n").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-E", "^needle", path]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("needle first\nneedle second\n", search(&[]));
+        assert_eq!("needle second\n", search(&["--no-strip-bom"]));
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "-E", "^needle f", dir.path().to_str().unwrap()]), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("\n1:needle first\n"));
+    }
+
+    #[test]
+    fn many_literal_patterns_match_like_one_at_a_time() {
+        let words = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa", "Straße"];
+        let contents = "no greek here\nALPHA and omega\nthe zeta function\nmeta data\nstrasse\nSTRAßE\niot\nbeta\n";
+
+        for case_sensitive in [true, false] {
+            let mut args: Vec<String> = words.iter().map(|word| format!("-e={}", word)).collect();
+            args.push("-E=^no".to_string());
+            args.push("f".to_string());
+            let mut combined = config(&args.iter().map(String::as_str).collect::<Vec<_>>());
+            combined.case_sensitive = case_sensitive;
+            let matcher = Matcher::new(&combined).unwrap();
+
+            let naive: Vec<&str> = contents.lines()
+                                           .filter(|line| {
+                                               let folded = if case_sensitive { line.to_string() } else { line.to_lowercase() };
+                                               line.starts_with("no")
+                                                   || words.iter().any(|word| {
+                                                       let word = if case_sensitive { word.to_string() } else { word.to_lowercase() };
+                                                       folded.contains(&word)
+                                                   })
+                                           })
+                                           .collect();
+            assert_eq!(naive, search_with(&matcher, contents));
+        }
+    }
+
+    #[test]
+    fn no_unicode_makes_regex_classes_ascii() {
+        let contents = "naïve\nété\nÉTÉ\n";
+        let unicode = Matcher::new(&config(&["-E", r"^\w+$", "f"])).unwrap();
+        assert_eq!(vec!["naïve", "été", "ÉTÉ"], search_with(&unicode, contents));
+
+        let ascii = Matcher::new(&config(&["--no-unicode", "-E", r"^\w+$", "f"])).unwrap();
+        assert!(search_with(&ascii, contents).is_empty());
+        let words = Matcher::new(&config(&["--no-unicode", "-E", r"\w+", "f"])).unwrap();
+        assert_eq!(vec![0..2, 4..6], words.find_spans("naïve"));
+
+        // `.` matches a single byte, but spans still cover whole characters.
+        let any = Matcher::new(&config(&["--no-unicode", "-E", "^.", "f"])).unwrap();
+        assert_eq!(vec![0..2], any.find_spans("été"));
+        assert_eq!(("é<t>é".to_string(), 1), words.replace("été", "<$0>"));
+
+        let mut folded = config(&["--no-unicode", "-E", "été", "f"]);
+        folded.case_sensitive = false;
+        assert_eq!(vec!["été"], search_with(&Matcher::new(&folded).unwrap(), contents));
+    }
+
+    #[test]
+    fn relative_to_rewrites_paths_under_the_base() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("repo/src")).unwrap();
+        fs::write(dir.path().join("repo/src/a.txt"), "needle\n").unwrap();
+        let root = dir.path().join("repo/src");
+        let root = root.to_str().unwrap();
+
+        let heading = |base: &Path| {
+            let relative_to = format!("--relative-to={}", base.display());
+            let mut out = Vec::new();
+            run_with_writer(config(&["-r", &relative_to, "needle", root]), &mut out).unwrap();
+            String::from_utf8(out).unwrap().lines().next().unwrap().to_string()
+        };
+
+        assert_eq!("File: src/a.txt", heading(&dir.path().join("repo")));
+        assert_eq!("File: src/a.txt", heading(&dir.path().join("repo/src/../../repo")));
+        let elsewhere = tempfile::tempdir().unwrap();
+        assert_eq!(format!("File: {}/a.txt", root), heading(elsewhere.path()));
+    }
+
+    #[test]
+    fn sort_by_relevance_puts_the_most_matches_first() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "one cat\ncat cat cat\na long line with a cat\ncat cat\ncat\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "cat cat cat cat\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "cat\n").unwrap();
+        let search = |args: &[&str]| {
+            let mut out = Vec::new();
+            run_with_writer(config(args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let a = dir.path().join("a.txt");
+        assert_eq!(
+            "cat cat cat\ncat cat\ncat\none cat\na long line with a cat\n",
+            search(&["--sort-by-relevance", "cat", a.to_str().unwrap()])
+        );
+
+        let files: Vec<String> = search(&["--sort-by-relevance", "-r", "cat", dir.path().to_str().unwrap()])
+            .lines()
+            .filter_map(|line| line.strip_prefix("File: ").map(|path| path.rsplit('/').next().unwrap().to_string()))
+            .collect();
+        assert_eq!(vec!["a.txt", "b.txt", "c.txt"], files);
+        assert_eq!((4, 800), relevance([("cat cat cat cat", vec![0..3, 4..7, 8..11, 12..15])]));
+    }
+
+    #[test]
+    fn sorted_files_with_equal_relevance_are_ordered_by_path() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["m.txt", "c.txt", "x.txt", "a.txt", "k.txt", "b.txt"] {
+            fs::write(dir.path().join(name), "needle\n").unwrap();
+        }
+        fs::write(dir.path().join("z.txt"), "needle needle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        for _ in 0..5 {
+            let mut out = Vec::new();
+            run_with_writer(config(&["--sort-by-relevance", "-r", "needle", root]), &mut out).unwrap();
+            let files: Vec<String> = String::from_utf8(out).unwrap()
+                                                           .lines()
+                                                           .filter_map(|line| line.strip_prefix("File: "))
+                                                           .map(|path| path[root.len() + 1..].to_string())
+                                                           .collect();
+            assert_eq!(vec!["z.txt", "a.txt", "b.txt", "c.txt", "k.txt", "m.txt", "x.txt"], files);
+        }
+    }
+
+    #[test]
+    fn context_boundary_keeps_context_within_a_record() {
+        let contents = "name: a\nstatus: ok\n---\nname: b\nstatus: failed\n---\nname: c\n";
+        let matcher = Matcher::new(&config(&["--context-boundary=---", "failed", "f"])).unwrap();
+        let lines: Vec<&str> = search_context(&matcher, contents, 3, 3).iter().map(|l| l.line).collect();
+        assert_eq!(vec!["name: b", "status: failed"], lines);
+
+        let matcher = Matcher::new(&config(&["--context-boundary=---", "name", "f"])).unwrap();
+        let groups = search_context_groups(&matcher, contents, 1, 1);
+        let groups: Vec<Vec<usize>> = groups.iter().map(|group| group.iter().map(|l| l.line_num).collect()).collect();
+        assert_eq!(vec![vec![1, 2], vec![4, 5], vec![7]], groups);
+
+        let unbounded = Matcher::new(&config(&["failed", "f"])).unwrap();
+        assert_eq!(5, search_context(&unbounded, contents, 2, 2).len());
+    }
+
+    #[test]
+    fn stats_report_the_bytes_read() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nhay\n").unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "more hay\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["--stats", "-r", "needle", root]), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (_, stats) = out.split_once("\n\nFiles searched: ").unwrap();
+        let lines: Vec<&str> = stats.lines().collect();
+        assert_eq!("2", lines[0]);
+        assert_eq!(format!("Bytes read: {}", 11 + 9), lines[1]);
+        assert!(lines[2].starts_with("Elapsed: ") && lines[3].starts_with("Throughput: "));
+
+        assert_eq!(None, throughput(100, Duration::ZERO));
+        assert_eq!(Some(2.0), throughput(1_000_000, Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn reverse_flips_the_order_of_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "needle needle\n").unwrap();
+        fs::write(dir.path().join("sub/c.txt"), "needle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let files = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-r", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+                                  .lines()
+                                  .filter_map(|line| line.strip_prefix("File: "))
+                                  .map(|path| path[root.len() + 1..].to_string())
+                                  .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["sub/c.txt", "b.txt", "a.txt"], files(&["--sort=path", "--reverse"]));
+        assert_eq!(vec!["sub/c.txt", "b.txt", "a.txt"], files(&["--reverse"]));
+        assert_eq!(vec!["sub/c.txt", "a.txt", "b.txt"], files(&["--sort-by-relevance", "--reverse"]));
+    }
+
+    #[test]
+    fn hyperlink_wraps_paths_in_osc_8_links() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hay\nneedle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let path = format!("{}/a.txt", root);
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-r", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let link = |url: &str| format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, path);
+        assert!(search(&["--hyperlink", "--color=always"])
+            .starts_with(&format!("File: {}\n", link(&format!("file://{}#L1", path)))));
+        assert_eq!(
+            format!("{}:2\n", link(&format!("editor://open?file={}&line=2", path))),
+            search(&["--hyperlink-format=editor://open?file={path}&line={line}", "--color=always", "--line-numbers-only"])
+        );
+        assert!(search(&["--hyperlink", "--color=never"]).starts_with(&format!("File: {}\n", path)));
+    }
+
+    #[test]
+    fn squeeze_blank_prints_one_of_each_run_of_blank_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        fs::write(&path, "a\n\n\n  \nb\n\nc\n").unwrap();
+        let path = path.to_str().unwrap();
+        let run = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.push(path);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("1~a\n2~\n5:b\n6~\n7~c\n", run(&["--passthru", "--squeeze-blank", "b"]));
+        assert_eq!("1~a\n2~\n3~\n4~  \n5:b\n6~\n7~c\n", run(&["--passthru", "b"]));
+        assert_eq!("a\n\nc\n", run(&["--squeeze-blank", "-E", r"^\s*$|a|c"]));
+        assert_eq!("2~\n5:b\n6~\n7~c\n", run(&["--squeeze-blank", "-B=3", "-A=2", "b"]));
+    }
+
+    #[cfg(all(feature = "bzip2", feature = "xz"))]
+    #[test]
+    fn compressed_files_are_searched_decompressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz2.write_all(b"hay\nneedle in bz2\n").unwrap();
+        fs::write(dir.path().join("a.txt.bz2"), bz2.finish().unwrap()).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(b"needle in xz\nhay\n").unwrap();
+        fs::write(dir.path().join("b.log.xz"), xz.finish().unwrap()).unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let bz2_path = dir.path().join("a.txt.bz2");
+        let mut out = Vec::new();
+        run_with_writer(config(&["needle", bz2_path.to_str().unwrap()]), &mut out).unwrap();
+        assert_eq!("needle in bz2\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--sort=path", "needle", root]), &mut out).unwrap();
+        assert_eq!(
+            format!("File: {root}/a.txt.bz2\n2:needle in bz2\n\nFile: {root}/b.log.xz\n1:needle in xz\n", root = root),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_skipped_or_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\n").unwrap();
+        let fifo = dir.path().join("pipe");
+        assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+        let socket = dir.path().join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        // Opening the FIFO would wait for a writer forever.
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "needle", root]), &mut out).unwrap();
+        assert_eq!(format!("File: {}/a.txt\n1:needle\n", root), String::from_utf8(out).unwrap());
+
+        let err = run_with_writer(config(&["needle", socket.to_str().unwrap()]), &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().ends_with("is a socket, which minigrep can't search"), "{}", err);
+
+        // Named directly, it's read once something writes to it, and waited
+        // on only so long for that.
+        let writer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || fs::write(fifo, "hay\nneedle\n").unwrap())
+        };
+        let mut out = Vec::new();
+        run_with_writer(config(&["needle", fifo.to_str().unwrap()]), &mut out).unwrap();
+        writer.join().unwrap();
+        assert_eq!("needle\n", String::from_utf8(out).unwrap());
+
+        let err = run_with_writer(config(&["needle", fifo.to_str().unwrap()]), &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("is a named pipe that nothing wrote to"), "{}", err);
+
+        // `--max-runtime` gives a slow writer longer to start, but bounds
+        // the whole read.
+        let writer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(2500));
+                fs::write(fifo, "needle\n").unwrap()
+            })
+        };
+        let mut out = Vec::new();
+        run_with_writer(config(&["--max-runtime=10", "needle", fifo.to_str().unwrap()]), &mut out).unwrap();
+        writer.join().unwrap();
+        assert_eq!("needle\n", String::from_utf8(out).unwrap());
+
+        let writer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || {
+                let mut pipe = fs::OpenOptions::new().write(true).open(fifo).unwrap();
+                pipe.write_all(b"needle\n").unwrap();
+                std::thread::sleep(Duration::from_millis(1000));
+            })
+        };
+        let err = run_with_writer(config(&["--max-runtime=0.2", "needle", fifo.to_str().unwrap()]), &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("is a named pipe still being written to after 0.2s"), "{}", err);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn line_terminator_splits_records_on_another_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.dat");
+        fs::write(&path, "alpha\nbeta\0gamma needle\0delta\nneedle\0epsilon\0").unwrap();
+        let path = path.to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.push(path);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("gamma needle\0delta\nneedle\0", search(&["-z", "needle"]));
+        assert_eq!("gamma needle\0delta\nneedle\0", search(&["--null-data", "-E", "^(gamma|delta)"]));
+        assert_eq!("alpha\nbeta\0", search(&["--line-terminator=\\0", "-E", "a$"]));
+        assert_eq!("1~alpha\nbeta\x002:gamma needle\x003:delta\nneedle\x004~epsilon\0", search(&["-z", "-C=1", "needle"]));
+        assert_eq!("alpha\nbeta\0gamma needle\0delta\n", search(&["--line-terminator=\n", "a"]));
+
+        fs::write(path, "one;two needle;three").unwrap();
+        assert_eq!("two needle;", search(&["--line-terminator=;", "needle"]));
+        assert!(Config::new(["minigrep", "--line-terminator=ab", "x", path].iter().map(|s| s.to_string())).is_err());
+        assert!(Config::new(["minigrep", "-z", "-r", "x", path].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn fail_fast_stops_at_an_unreadable_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), b"needle \xff\xfe\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "needle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--sort=path", "needle", root]), &mut out).unwrap();
+        assert_eq!(
+            format!("File: {root}/a.txt\n1:needle\n\nFile: {root}/c.txt\n1:needle\n", root = root),
+            String::from_utf8(out).unwrap()
+        );
+
+        let err = run_with_writer(config(&["-r", "--fail-fast", "needle", root]), &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::Io(_)));
+        assert!(err.to_string().starts_with(&format!("{}/b.txt: ", root)), "{}", err);
+    }
+
+    #[test]
+    fn nth_prints_only_that_match_counting_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        fs::write(&a, "needle 1\nhay\nneedle 2\nhay\n").unwrap();
+        fs::write(&b, "needle 3\nneedle 4\n").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        let search = |args: &[&str]| {
+            let mut out = Vec::new();
+            run_with_writer(config(args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("needle 2\n", search(&["--nth=2", "needle", a]));
+        assert_eq!("2~hay\n3:needle 2\n4~hay\n", search(&["--nth=2", "-C=1", "needle", a]));
+        assert_eq!(format!("File: {}\nneedle 3\n", b), search(&["--nth=3", "needle", a, b]));
+        assert_eq!("", search(&["--nth=5", "needle", a, b]));
+
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::create_dir(&first).unwrap();
+        fs::create_dir(&second).unwrap();
+        fs::write(first.join("c.txt"), "needle\n").unwrap();
+        fs::write(second.join("d.txt"), "hay\nneedle\n").unwrap();
+        let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+        assert_eq!(format!("File: {}/d.txt\nneedle\n", second), search(&["-r", "--nth=2", "needle", first, second]));
+
+        assert!(Config::new(["minigrep", "--nth=0", "needle", a].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dedupe_files_searches_a_hard_linked_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\n").unwrap();
+        fs::hard_link(dir.path().join("a.txt"), dir.path().join("b.txt")).unwrap();
+        let root = dir.path().to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut out = Vec::new();
+            run_with_writer(config(args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            format!("File: {root}/a.txt\n1:needle\n\nFile: {root}/b.txt\n1:needle\n", root = root),
+            search(&["-r", "--sort=path", "needle", root])
+        );
+        assert_eq!(
+            format!("File: {root}/a.txt\n1:needle\n", root = root),
+            search(&["-r", "--sort=path", "--dedupe-files", "needle", root])
+        );
+        assert_eq!(format!("{root}/a.txt:1\n", root = root), search(&["-r", "-c", "--dedupe-files", "needle", root]));
+        assert_eq!(format!("{root}/a.txt:1\nTotal: 1\n", root = root), search(&["-r", "--count-unique", "--dedupe-files", "needle", root]));
+    }
+
+    #[test]
+    fn min_matches_leaves_out_files_with_fewer_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nhay\nneedle needle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "needle\nhay\n").unwrap();
+        fs::write(dir.path().join("sub/c.txt"), "needle\nneedle\nneedle\n").unwrap();
+        fs::write(dir.path().join("d.txt"), "hay\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-r", "--sort=path", "--min-matches=2", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            format!("File: {root}/a.txt\n1:needle\n--\n3:needle needle\n\nFile: {root}/sub/c.txt\n1:needle\n2:needle\n3:needle\n", root = root),
+            search(&[])
+        );
+        assert_eq!(format!("{root}/a.txt:2\n{root}/sub/c.txt:3\n", root = root), search(&["--count"]));
+        assert!(any_match(&config(&["-q", "-r", "--min-matches=3", "needle", root])).unwrap());
+        assert!(!any_match(&config(&["-q", "-r", "--min-matches=4", "needle", root])).unwrap());
+        assert!(Config::new(["minigrep", "--min-matches=2", "needle", root].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn run_returns_the_number_of_matches_printed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nneedle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "needle\nhay\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let found = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["needle", root]);
+            run_with_writer(config(&args), &mut Vec::new()).unwrap()
+        };
+
+        assert_eq!(3, found(&["-r"]));
+        assert_eq!(2, found(&["-r", "--min-matches=2"]));
+        assert_eq!(2, found(&["-r", "--count", "--min-matches=2"]));
+        assert_eq!(0, found(&["-r", "--min-matches=3"]));
+
+        // Matching lines are counted, not the paragraphs or sentences printed.
+        let prose = dir.path().join("prose.txt");
+        fs::write(&prose, "A needle. And another needle\nhere.\nneedle\n\nhay\n").unwrap();
+        let prose = prose.to_str().unwrap();
+        assert_eq!(2, run_with_writer(config(&["--paragraph", "needle", prose]), &mut Vec::new()).unwrap());
+        assert_eq!(2, run_with_writer(config(&["--sentence-context", "needle", prose]), &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn flat_prefixes_every_line_with_its_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "needle\nhay\nhay\nhay\nneedle\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay\nneedle\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let search = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["-r", "--sort=path", "--flat", "needle", root]);
+            let mut out = Vec::new();
+            run_with_writer(config(&args), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            format!("{root}/a.txt:1:needle\n{root}/a.txt:5:needle\n{root}/b.txt:2:needle\n", root = root),
+            search(&[])
+        );
+        assert_eq!(
+            format!(
+                "{root}/a.txt:1:needle\n{root}/a.txt:2~hay\n--\n{root}/a.txt:4~hay\n{root}/a.txt:5:needle\n\
+                 --\n{root}/b.txt:1~hay\n{root}/b.txt:2:needle\n",
+                root = root
+            ),
+            search(&["-C=1"])
+        );
+        assert!(Config::new(["minigrep", "--flat", "needle", root].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn show_type_labels_file_headings() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.rs", "b.py", "c.xyz", "Makefile"] {
+            fs::write(dir.path().join(name), "needle\n").unwrap();
+        }
+        let root = dir.path().to_str().unwrap();
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["-r", "--sort=path", "--show-type", "needle", root]), &mut out).unwrap();
+        let headings: Vec<_> = String::from_utf8(out).unwrap()
+                                                     .lines()
+                                                     .filter_map(|line| line.strip_prefix("File: "))
+                                                     .map(|heading| heading[root.len() + 1..].to_string())
+                                                     .collect();
+        assert_eq!(vec!["Makefile [make]", "a.rs [rust]", "b.py [python]", "c.xyz [unknown]"], headings);
+    }
+
+    #[test]
+    fn globs_in_file_names_are_expanded() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("logs/old")).unwrap();
+        for name in ["a.txt", "b.txt", "c.log", ".hidden.txt", "logs/x.txt", "logs/old/y.txt"] {
+            fs::write(dir.path().join(name), "needle\n").unwrap();
+        }
+        let root = dir.path().to_str().unwrap();
+        let expanded = |glob: &str| {
+            config(&["needle", &format!("{}/{}", root, glob)]).filenames
+                                                             .iter()
+                                                             .map(|path| path[root.len() + 1..].to_string())
+                                                             .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["a.txt", "b.txt"], expanded("*.txt"));
+        assert_eq!(vec![".hidden.txt"], expanded(".*.txt"));
+        assert_eq!(vec!["a.txt", "c.log"], expanded("[ac].*"));
+        assert_eq!(vec!["b.txt", "c.log", "logs"], expanded("[!a]?*"));
+        assert_eq!(vec!["logs/x.txt"], expanded("l*/*.txt"));
+        assert_eq!(vec!["logs/old/y.txt"], expanded("*/*/?.txt"));
+        assert_eq!(vec!["*.md"], expanded("*.md"));
+
+        let mut out = Vec::new();
+        run_with_writer(config(&["needle", &format!("{}/*.txt", root)]), &mut out).unwrap();
+        assert_eq!(
+            format!("File: {root}/a.txt\nneedle\n\nFile: {root}/b.txt\nneedle\n", root = root),
+            String::from_utf8(out).unwrap()
+        );
     }
 }
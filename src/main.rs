@@ -3,16 +3,77 @@ extern crate minigrep;
 use std::process;
 use std::env;
 
-use minigrep::Config;
+use minigrep::{Config, Error, ErrorFormat};
+
+/// Exit code for a run interrupted by Ctrl-C, following the shell convention
+/// of 128 + SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Exit code for errors under `--quiet`, where 1 already means "no match".
+const EXIT_ERROR: i32 = 2;
+
+/// Exit code when `--fail-if-matches` or `--fail-if-no-matches` trips.
+const EXIT_THRESHOLD: i32 = 1;
 
 fn main() {
-    let config = Config::new(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {}", err);
+    let args: Vec<String> = env::args().collect();
+    let error_format = ErrorFormat::from_args(&args);
+
+    let config = Config::new(args.into_iter()).unwrap_or_else(|err| {
+        report(&Error::Config(err), error_format, "Problem parsing arguments");
         process::exit(1);
     });
 
-    if let Err(e) = minigrep::run(config) {
-        eprintln!("Application error: {}", e);
-        process::exit(1);
+    #[cfg(feature = "ctrlc")]
+    {
+        let cancel = std::sync::Arc::clone(&config.cancel);
+        let _ = ctrlc::set_handler(move || {
+            cancel.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+    }
+
+    if config.quiet {
+        match minigrep::any_match(&config) {
+            Ok(found) => process::exit(if found { 0 } else { 1 }),
+            Err(e @ Error::Cancelled { .. }) => {
+                report(&e, error_format, "Search stopped");
+                process::exit(EXIT_INTERRUPTED);
+            }
+            Err(e @ Error::TimedOut { .. }) => {
+                report(&e, error_format, "Search stopped");
+                process::exit(EXIT_ERROR);
+            }
+            Err(e) => {
+                report(&e, error_format, "Application error");
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+
+    let (fail_if_matches, fail_if_no_matches) = (config.fail_if_matches, config.fail_if_no_matches);
+    match minigrep::run(config) {
+        Ok(matches) if minigrep::fails_threshold(fail_if_matches, fail_if_no_matches, matches) => {
+            process::exit(EXIT_THRESHOLD)
+        }
+        Ok(_) => {}
+        Err(e @ Error::Cancelled { .. }) => {
+            report(&e, error_format, "Search stopped");
+            process::exit(EXIT_INTERRUPTED);
+        }
+        Err(e @ Error::TimedOut { .. }) => {
+            report(&e, error_format, "Search stopped");
+            process::exit(1);
+        }
+        Err(e) => {
+            report(&e, error_format, "Application error");
+            process::exit(1);
+        }
+    }
+}
+
+fn report(err: &Error, format: ErrorFormat, context: &str) {
+    match format {
+        ErrorFormat::Text => eprintln!("{}: {}", context, err),
+        ErrorFormat::Json => eprintln!("{}", err.to_json()),
     }
 }
@@ -0,0 +1,768 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::{Range, RangeInclusive};
+
+use aho_corasick::AhoCorasick;
+use regex::{bytes, Regex, RegexBuilder};
+
+use crate::{Config, Error, QueryPattern};
+#[cfg(feature = "script")]
+use crate::{script::Script, ConfigError};
+
+/// How many literal patterns it takes before they're matched all at once
+/// with an Aho-Corasick automaton, rather than one after another.
+const LITERAL_SET_MIN: usize = 8;
+
+/// Decides whether a line matches: the line must contain the query, or any
+/// of the `-e`/`-E=` patterns when those are given, and none of the
+/// `--and-not` patterns, be as long as `--min-line-length` and
+/// `--max-line-length` allow, and, for the `_at` checks, fall inside one of
+/// the `--line-range` ranges when any are given. With `--field`, the patterns
+/// only see that field of the line. Lines longer than `--max-line-bytes`
+/// never match. With `--within-tag` or `--section-start`, the `_at` checks
+/// also need the line to be inside the tag or a section, which depends on the
+/// file: see `for_contents`.
+///
+/// With `-v` the query checks are inverted, so a line matches when it fails
+/// them, but lines outside the ranges, the tag or the sections, or too long,
+/// still never match.
+#[derive(Clone)]
+pub struct Matcher {
+    /// Alternatives: a line matches if any of these does.
+    queries: Vec<Pattern>,
+    /// The literal `queries` combined, when there are enough of them to be
+    /// worth it. Only speeds up deciding whether a line matches.
+    literal_set: Option<LiteralSet>,
+    /// Decides instead of `queries` when given.
+    #[cfg(feature = "script")]
+    script: Option<Script>,
+    excluded: Vec<Pattern>,
+    line_ranges: Vec<(usize, Option<usize>)>,
+    field: Option<usize>,
+    delimiter: Option<char>,
+    max_line_bytes: Option<usize>,
+    within_tag: Option<String>,
+    section_start: Option<Pattern>,
+    section_end: Option<Pattern>,
+    /// Which lines of the current file are inside `within_tag` and a section.
+    region_lines: Vec<bool>,
+    context_boundary: Option<String>,
+    invert: bool,
+    /// Let `find_spans` report matches that overlap each other.
+    overlapping: bool,
+    /// Lines starting with this, after any indentation, never match.
+    comment_prefix: Option<String>,
+    /// Make each replacement follow the case of the text it replaces.
+    preserve_case: bool,
+    /// Only match in files where every pattern matches somewhere.
+    all_patterns: bool,
+    /// Set by `for_contents` for a file that `all_patterns` rules out.
+    skip_file: bool,
+    /// The lengths a matching line may have, from `--min-line-length` and
+    /// `--max-line-length`.
+    line_length: RangeInclusive<usize>,
+    /// Measure `line_length` in bytes instead of characters.
+    line_length_bytes: bool,
+}
+
+/// Every literal pattern in one automaton, so a line is scanned once rather
+/// than once per pattern.
+#[derive(Clone)]
+struct LiteralSet {
+    automaton: AhoCorasick,
+    /// When `false` the patterns are lowercased already, as for `Pattern`.
+    case_sensitive: bool,
+}
+
+#[derive(Clone)]
+enum Pattern {
+    /// A plain substring. When matching ignores case, `needle` is already
+    /// lowercased.
+    Literal { needle: String, case_sensitive: bool },
+    Regex(Regex),
+    /// Used by `--byte-mode`, so lines don't have to be valid UTF-8.
+    BytesRegex(bytes::Regex),
+}
+
+/// Memoizes compiled regexes by pattern and the flags they were built with,
+/// so a pattern given more than once is only compiled once.
+#[derive(Default)]
+pub struct RegexCache {
+    compiled: HashMap<(String, bool, bool), Regex>,
+    compiles: usize,
+}
+
+impl RegexCache {
+    pub fn new() -> RegexCache {
+        RegexCache::default()
+    }
+
+    pub fn get(&mut self, pattern: &str, case_insensitive: bool, multiline: bool) -> Result<Regex, regex::Error> {
+        let key = (pattern.to_string(), case_insensitive, multiline);
+        if let Some(re) = self.compiled.get(&key) {
+            return Ok(re.clone());
+        }
+
+        let re = RegexBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .multi_line(multiline)
+            .build()?;
+        self.compiles += 1;
+        self.compiled.insert(key, re.clone());
+        Ok(re)
+    }
+
+    /// How many regexes were actually compiled, as opposed to reused.
+    pub fn compiles(&self) -> usize {
+        self.compiles
+    }
+}
+
+impl Matcher {
+    pub fn new(config: &Config) -> Result<Matcher, Error> {
+        Matcher::with_cache(config, &mut RegexCache::new())
+    }
+
+    /// Like `new`, but compiles regexes through `cache`, so callers building
+    /// several matchers can share compiled patterns.
+    pub fn with_cache(config: &Config, cache: &mut RegexCache) -> Result<Matcher, Error> {
+        let queries = if config.patterns.is_empty() {
+            vec![Pattern::new(&config.query, config.regex, config, cache)?]
+        } else {
+            config.patterns
+                  .iter()
+                  .map(|pattern| match pattern {
+                      QueryPattern::Literal(literal) => Pattern::new(literal, false, config, cache),
+                      QueryPattern::Regex(regex) => Pattern::new(regex, true, config, cache),
+                  })
+                  .collect::<Result<_, _>>()?
+        };
+
+        Ok(Matcher {
+            literal_set: LiteralSet::new(&queries, config.case_sensitive),
+            queries,
+            #[cfg(feature = "script")]
+            script: config.script
+                          .as_deref()
+                          .map(Script::new)
+                          .transpose()
+                          .map_err(ConfigError::InvalidScript)?,
+            excluded: config.and_not
+                            .iter()
+                            .map(|pattern| Pattern::new(pattern, config.regex, config, cache))
+                            .collect::<Result<_, _>>()?,
+            line_ranges: config.line_ranges.clone(),
+            field: config.field,
+            delimiter: config.delimiter,
+            max_line_bytes: config.max_line_bytes,
+            within_tag: config.within_tag.clone(),
+            section_start: config.section_start
+                                 .as_deref()
+                                 .map(|pattern| Pattern::new(pattern, config.regex, config, cache))
+                                 .transpose()?,
+            section_end: config.section_end
+                               .as_deref()
+                               .map(|pattern| Pattern::new(pattern, config.regex, config, cache))
+                               .transpose()?,
+            region_lines: Vec::new(),
+            context_boundary: config.context_boundary.clone(),
+            invert: config.invert,
+            overlapping: config.overlapping,
+            comment_prefix: config.no_comments
+                                  .then(|| config.comment_prefix.clone().unwrap_or_else(|| "#".to_string())),
+            preserve_case: config.preserve_case,
+            all_patterns: config.all_patterns,
+            skip_file: false,
+            line_length: config.min_line_length.unwrap_or(0)..=config.max_line_length.unwrap_or(usize::MAX),
+            line_length_bytes: config.line_length_bytes,
+        })
+    }
+
+    /// The matcher to use for the lines of `contents`. Only `--within-tag`,
+    /// `--section-start` and `--all-patterns` need to look at the file first;
+    /// otherwise this is `self`.
+    pub fn for_contents(&self, contents: &str) -> Cow<'_, Matcher> {
+        if self.all_patterns && !self.has_every_pattern(contents) {
+            let mut matcher = self.clone();
+            matcher.skip_file = true;
+            return Cow::Owned(matcher);
+        }
+        if !self.has_regions() {
+            return Cow::Borrowed(self);
+        }
+
+        let mut matcher = self.clone();
+        matcher.region_lines = match &self.within_tag {
+            Some(tag) => tag_lines(contents, tag),
+            None => vec![true; contents.lines().count()],
+        };
+        if let Some(start) = &self.section_start {
+            let sections = section_lines(contents, start, self.section_end.as_ref());
+            for (inside, in_section) in matcher.region_lines.iter_mut().zip(sections) {
+                *inside &= in_section;
+            }
+        }
+        Cow::Owned(matcher)
+    }
+
+    /// Whether each of the patterns matches somewhere in `contents`, not
+    /// necessarily on the same line.
+    fn has_every_pattern(&self, contents: &str) -> bool {
+        self.queries.iter().all(|pattern| {
+            contents.lines()
+                    .filter(|line| !self.too_long(line.len()))
+                    .filter_map(|line| self.searched_text(line))
+                    .any(|(_, text)| pattern.is_match(text))
+        })
+    }
+
+    /// Whether only some regions of each file are searched, so `for_contents`
+    /// has work to do.
+    fn has_regions(&self) -> bool {
+        self.within_tag.is_some() || self.section_start.is_some()
+    }
+
+    /// Whether the 1-based `line_num` is inside the searched line ranges.
+    pub fn in_range(&self, line_num: usize) -> bool {
+        if self.skip_file {
+            return false;
+        }
+        let in_region = !self.has_regions() || self.region_lines.get(line_num - 1) == Some(&true);
+        in_region
+            && (self.line_ranges.is_empty()
+                || self.line_ranges.iter().any(|&(start, end)| {
+                    line_num >= start && end.is_none_or(|end| line_num <= end)
+                }))
+    }
+
+    /// Indexes of the lines to print for a match at index `i` of `lines`:
+    /// up to `before` lines before it and `after` lines after it, stopping
+    /// short of any `--context-boundary` line.
+    pub fn context_window(&self, lines: &[&str], i: usize, before: usize, after: usize) -> RangeInclusive<usize> {
+        let is_boundary = |k: usize| self.context_boundary.as_deref() == Some(lines[k]);
+
+        let mut start = i;
+        while start > 0 && i - start < before && !is_boundary(start - 1) {
+            start -= 1;
+        }
+        let mut end = i;
+        while end + 1 < lines.len() && end - i < after && !is_boundary(end + 1) {
+            end += 1;
+        }
+        start..=end
+    }
+
+    /// `is_match` for the line at the 1-based `line_num`.
+    pub fn is_match_at(&self, line_num: usize, line: &str) -> bool {
+        self.in_range(line_num) && self.is_match(line)
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        if self.too_long(line.len()) || self.is_comment(line) {
+            return false;
+        }
+        let found = match self.searched_text(line) {
+            Some((_, text)) => {
+                self.query_matches(text) && !self.excluded.iter().any(|p| p.is_match(text)) && self.fits_length(line)
+            }
+            None => false,
+        };
+        found != self.invert
+    }
+
+    /// Whether `line` is as long as `--min-line-length` and
+    /// `--max-line-length` allow.
+    fn fits_length(&self, line: &str) -> bool {
+        let len = if self.line_length_bytes { line.len() } else { line.chars().count() };
+        self.line_length.contains(&len)
+    }
+
+    /// Like `is_match`, but for lines that may not be valid UTF-8.
+    pub fn is_match_bytes(&self, line: &[u8]) -> bool {
+        if self.comment_prefix.as_ref().is_some_and(|prefix| line.trim_ascii_start().starts_with(prefix.as_bytes())) {
+            return false;
+        }
+        let found = self.queries.iter().any(|p| p.is_match_bytes(line))
+                    && !self.excluded.iter().any(|p| p.is_match_bytes(line))
+                    && self.fits_length(&String::from_utf8_lossy(line));
+        !self.too_long(line.len()) && found != self.invert
+    }
+
+    /// Byte ranges of every occurrence of the query in `line`, sorted, with
+    /// overlapping occurrences of different patterns merged. With
+    /// `--overlapping`, occurrences may overlap and aren't merged. Lines
+    /// matched with `-v` contain no occurrences.
+    pub fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        match self.searched_text(line) {
+            Some(_) if self.invert => Vec::new(),
+            Some((offset, text)) if self.overlapping => {
+                let mut spans: Vec<Range<usize>> = self.queries
+                                                       .iter()
+                                                       .flat_map(|p| p.find_overlapping(text))
+                                                       .map(|span| span.start + offset..span.end + offset)
+                                                       .collect();
+                spans.sort_by_key(|span| (span.start, span.end));
+                spans.dedup();
+                spans
+            }
+            Some((offset, text)) => {
+                let mut spans: Vec<Range<usize>> = self.queries
+                                                       .iter()
+                                                       .flat_map(|p| p.find_spans(text))
+                                                       .map(|span| span.start + offset..span.end + offset)
+                                                       .collect();
+                if self.queries.len() > 1 {
+                    spans.sort_by_key(|span| (span.start, span.end));
+                    spans.dedup_by(|next, prev| {
+                        let overlaps = next.start < prev.end;
+                        if overlaps {
+                            prev.end = prev.end.max(next.end);
+                        }
+                        overlaps
+                    });
+                }
+                spans
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// `line` with every occurrence of the query replaced, and how many
+    /// replacements were made. Regex replacements may refer to capture
+    /// groups as `$1` or `$name`, and use the case changes `\U`, `\L`, `\u`,
+    /// `\l` and `\E`, except when several patterns are given.
+    pub fn replace(&self, line: &str, replacement: &str) -> (String, usize) {
+        if self.preserve_case {
+            return splice_with(line, &self.find_spans(line), |matched| match_case(matched, replacement));
+        }
+        match self.queries.as_slice() {
+            [query] => query.replace(line, replacement),
+            _ => splice(line, &self.find_spans(line), replacement),
+        }
+    }
+
+    #[cfg(feature = "script")]
+    fn query_matches(&self, text: &str) -> bool {
+        match &self.script {
+            Some(script) => script.is_match(text),
+            None => self.patterns_match(text),
+        }
+    }
+
+    #[cfg(not(feature = "script"))]
+    fn query_matches(&self, text: &str) -> bool {
+        self.patterns_match(text)
+    }
+
+    fn patterns_match(&self, text: &str) -> bool {
+        match &self.literal_set {
+            Some(set) => {
+                set.is_match(text)
+                    || self.queries.iter().any(|p| !matches!(p, Pattern::Literal { .. }) && p.is_match(text))
+            }
+            None => self.queries.iter().any(|p| p.is_match(text)),
+        }
+    }
+
+    /// Whether a line of `len` bytes is over `--max-line-bytes` and skipped.
+    pub fn too_long(&self, len: usize) -> bool {
+        self.max_line_bytes.is_some_and(|max| len > max)
+    }
+
+    /// Whether `line` is a comment that `--no-comments` keeps from matching.
+    fn is_comment(&self, line: &str) -> bool {
+        self.comment_prefix.as_ref().is_some_and(|prefix| line.trim_start().starts_with(prefix.as_str()))
+    }
+
+    /// The part of `line` the patterns apply to, with its byte offset: the
+    /// whole line, or the `--field` when one is set. `None` if the line has
+    /// too few fields or is a comment.
+    fn searched_text<'a>(&self, line: &'a str) -> Option<(usize, &'a str)> {
+        if self.is_comment(line) {
+            return None;
+        }
+        match self.field {
+            Some(n) => field_of(line, n, self.delimiter),
+            None => Some((0, line)),
+        }
+    }
+}
+
+/// Returns the 1-based field `n` of `line` and its byte offset. Fields are
+/// split on `delimiter`, or on runs of whitespace when there's none.
+pub fn field_of(line: &str, n: usize, delimiter: Option<char>) -> Option<(usize, &str)> {
+    let offset_of = |field: &str| field.as_ptr() as usize - line.as_ptr() as usize;
+
+    let field = match delimiter {
+        Some(delimiter) => line.split(delimiter).nth(n.checked_sub(1)?)?,
+        None => line.split_whitespace().nth(n.checked_sub(1)?)?,
+    };
+    Some((offset_of(field), field))
+}
+
+/// Flags each line of `contents` that is at least partly inside a `<tag>`
+/// element. This is a shallow scan for the opening and closing tags, line by
+/// line, that doesn't parse the markup: a line that opens the tag counts as
+/// inside, as does every line up to and including the one that closes it.
+pub fn tag_lines(contents: &str, tag: &str) -> Vec<bool> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut depth = 0usize;
+
+    contents.lines()
+            .map(|line| {
+                // `<tag>` or `<tag attr=...>`, but not `<tagname>` or `<tag/>`.
+                let opens = line.match_indices(&open)
+                                .filter(|(i, _)| {
+                                    let rest = &line[i + open.len()..];
+                                    rest.starts_with('>') || rest.starts_with(char::is_whitespace)
+                                })
+                                .count();
+                let closes = line.matches(&close).count();
+
+                let inside = depth > 0 || opens > 0;
+                depth = (depth + opens).saturating_sub(closes);
+                inside
+            })
+            .collect()
+}
+
+/// Flags each line of `contents` that is inside a section: the lines after
+/// one matching `start`, up to the next line matching `end` or `start`. The
+/// lines matching `start` and `end` mark the sections out and aren't in them.
+fn section_lines(contents: &str, start: &Pattern, end: Option<&Pattern>) -> Vec<bool> {
+    let mut inside = false;
+    contents.lines()
+            .map(|line| {
+                if start.is_match(line) {
+                    inside = true;
+                    false
+                } else if end.is_some_and(|end| end.is_match(line)) {
+                    inside = false;
+                    false
+                } else {
+                    inside
+                }
+            })
+            .collect()
+}
+
+/// `span` of `line` widened to whole characters, for bytes regexes that
+/// matched part of one.
+fn whole_chars(line: &str, span: Range<usize>) -> Range<usize> {
+    let start = (0..=span.start).rev().find(|&i| line.is_char_boundary(i)).unwrap_or(0);
+    let end = (span.end..=line.len()).find(|&i| line.is_char_boundary(i)).unwrap_or(line.len());
+    start..end
+}
+
+/// A case change in a regex replacement, as in Perl and sed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaseChange {
+    /// `\U`: uppercase until `\E` or `\L`.
+    Upper,
+    /// `\L`: lowercase until `\E` or `\U`.
+    Lower,
+    /// `\u`: uppercase the next character.
+    UpperNext,
+    /// `\l`: lowercase the next character.
+    LowerNext,
+    /// `\E`: end a `\U` or `\L`.
+    End,
+}
+
+/// Splits `replacement` at its case changes, pairing each piece with the
+/// change before it, or `None` if it has no case changes.
+fn case_changes(replacement: &str) -> Option<Vec<(Option<CaseChange>, &str)>> {
+    let bytes = replacement.as_bytes();
+    let mut pieces = Vec::new();
+    let mut change = None;
+    let mut start = 0;
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        let next = match (bytes[i], bytes[i + 1]) {
+            (b'\\', b'U') => CaseChange::Upper,
+            (b'\\', b'L') => CaseChange::Lower,
+            (b'\\', b'u') => CaseChange::UpperNext,
+            (b'\\', b'l') => CaseChange::LowerNext,
+            (b'\\', b'E') => CaseChange::End,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        pieces.push((change, &replacement[start..i]));
+        change = Some(next);
+        i += 2;
+        start = i;
+    }
+
+    if pieces.is_empty() {
+        return None;
+    }
+    pieces.push((change, &replacement[start..]));
+    Some(pieces)
+}
+
+/// Expands each of the `pieces` from `case_changes` with `expand`, which
+/// fills in capture groups, and changes the case of the results as asked.
+fn expand_cased<F: FnMut(&str, &mut String)>(pieces: &[(Option<CaseChange>, &str)], mut expand: F) -> String {
+    let mut replaced = String::new();
+    let mut whole = None;
+    let mut next = None;
+
+    for &(change, text) in pieces {
+        match change {
+            Some(CaseChange::Upper | CaseChange::Lower) => whole = change,
+            Some(CaseChange::End) => whole = None,
+            Some(CaseChange::UpperNext | CaseChange::LowerNext) => next = change,
+            None => {}
+        }
+
+        let mut expanded = String::new();
+        expand(text, &mut expanded);
+        let mut expanded = match whole {
+            Some(CaseChange::Upper) => expanded.to_uppercase(),
+            Some(CaseChange::Lower) => expanded.to_lowercase(),
+            _ => expanded,
+        };
+        // A `\u` or `\l` before an empty group waits for the next character.
+        if let (Some(change), Some(first)) = (next, expanded.chars().next()) {
+            let changed: String = match change {
+                CaseChange::UpperNext => first.to_uppercase().collect(),
+                _ => first.to_lowercase().collect(),
+            };
+            expanded.replace_range(..first.len_utf8(), &changed);
+            next = None;
+        }
+        replaced.push_str(&expanded);
+    }
+
+    replaced
+}
+
+/// `line` with each of `spans` replaced by `replacement`, and how many
+/// replacements that was.
+fn splice(line: &str, spans: &[Range<usize>], replacement: &str) -> (String, usize) {
+    splice_with(line, spans, |_| replacement.to_string())
+}
+
+/// Like `splice`, but with each span replaced by what `replacement` makes of
+/// the text it covers.
+fn splice_with<F: Fn(&str) -> String>(line: &str, spans: &[Range<usize>], replacement: F) -> (String, usize) {
+    let mut replaced = String::with_capacity(line.len());
+    let mut last = 0;
+    for span in spans {
+        replaced.push_str(&line[last..span.start]);
+        replaced.push_str(&replacement(&line[span.clone()]));
+        last = span.end;
+    }
+    replaced.push_str(&line[last..]);
+
+    (replaced, spans.len())
+}
+
+/// `replacement` in the case of `matched`, for `--preserve-case`: all
+/// lowercase, all uppercase, or title case (a capital followed by lowercase)
+/// like the matched letters. Any other mix, such as `camelCase`, and text
+/// without letters leave `replacement` as it is.
+pub fn match_case(matched: &str, replacement: &str) -> String {
+    let mut letters = matched.chars().filter(|c| c.is_alphabetic());
+    let first = match letters.next() {
+        Some(first) => first,
+        None => return replacement.to_string(),
+    };
+    let rest: Vec<char> = letters.collect();
+
+    if first.is_lowercase() && rest.iter().all(|c| c.is_lowercase()) {
+        replacement.to_lowercase()
+    } else if first.is_uppercase() && rest.iter().all(|c| c.is_lowercase()) {
+        let mut chars = replacement.chars();
+        chars.next()
+             .map(|c| c.to_uppercase().chain(chars.as_str().to_lowercase().chars()).collect())
+             .unwrap_or_default()
+    } else if first.is_uppercase() && rest.iter().all(|c| c.is_uppercase()) {
+        replacement.to_uppercase()
+    } else {
+        replacement.to_string()
+    }
+}
+
+impl LiteralSet {
+    /// The literal patterns among `queries` combined, or `None` if there are
+    /// too few of them to bother.
+    fn new(queries: &[Pattern], case_sensitive: bool) -> Option<LiteralSet> {
+        let needles: Vec<&str> = queries.iter()
+                                        .filter_map(|p| match p {
+                                            Pattern::Literal { needle, .. } => Some(needle.as_str()),
+                                            _ => None,
+                                        })
+                                        .collect();
+        if needles.len() < LITERAL_SET_MIN {
+            return None;
+        }
+
+        // Building only fails for sets far larger than any command line.
+        let automaton = AhoCorasick::new(needles).ok()?;
+        Some(LiteralSet { automaton, case_sensitive })
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        if self.case_sensitive {
+            self.automaton.is_match(line)
+        } else {
+            self.automaton.is_match(&line.to_lowercase())
+        }
+    }
+}
+
+impl Pattern {
+    fn new(pattern: &str, regex: bool, config: &Config, cache: &mut RegexCache) -> Result<Pattern, regex::Error> {
+        // Without Unicode a regex can match part of a character, which only
+        // a bytes regex allows.
+        if (config.byte_mode || config.no_unicode) && regex {
+            return bytes::RegexBuilder::new(pattern)
+                .case_insensitive(!config.case_sensitive)
+                .multi_line(config.multiline)
+                .unicode(!config.no_unicode)
+                .build()
+                .map(Pattern::BytesRegex);
+        }
+        if regex {
+            return cache.get(pattern, !config.case_sensitive, config.multiline)
+                        .map(Pattern::Regex);
+        }
+
+        let needle = if config.case_sensitive { pattern.to_string() } else { pattern.to_lowercase() };
+        Ok(Pattern::Literal { needle, case_sensitive: config.case_sensitive })
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            Pattern::Literal { needle, case_sensitive: true } => line.contains(needle.as_str()),
+            Pattern::Literal { needle, case_sensitive: false } => line.to_lowercase().contains(needle.as_str()),
+            Pattern::Regex(re) => re.is_match(line),
+            Pattern::BytesRegex(re) => re.is_match(line.as_bytes()),
+        }
+    }
+
+    /// Case-insensitive literals only fold ASCII here, since the line can't
+    /// be lowercased as text.
+    fn is_match_bytes(&self, line: &[u8]) -> bool {
+        match self {
+            Pattern::Literal { needle, case_sensitive } => {
+                let needle = needle.as_bytes();
+                if needle.is_empty() {
+                    return true;
+                }
+                line.windows(needle.len()).any(|window| {
+                    if *case_sensitive {
+                        window == needle
+                    } else {
+                        window.eq_ignore_ascii_case(needle)
+                    }
+                })
+            }
+            Pattern::Regex(re) => re.is_match(&String::from_utf8_lossy(line)),
+            Pattern::BytesRegex(re) => re.is_match(line),
+        }
+    }
+
+    /// Literal patterns replace their text as is, with no `$` expansion in
+    /// `replacement`.
+    fn replace(&self, line: &str, replacement: &str) -> (String, usize) {
+        match self {
+            Pattern::Regex(re) => {
+                // `replace_all` doesn't say how many replacements it made.
+                let count = re.captures_iter(line).count();
+                let replaced = match case_changes(replacement) {
+                    Some(pieces) => re.replace_all(line, |caps: &regex::Captures| {
+                        expand_cased(&pieces, |text, dst| caps.expand(text, dst))
+                    }),
+                    None => re.replace_all(line, replacement),
+                };
+                (replaced.into_owned(), count)
+            }
+            Pattern::BytesRegex(re) => {
+                let count = re.captures_iter(line.as_bytes()).count();
+                let replaced = match case_changes(replacement) {
+                    Some(pieces) => re.replace_all(line.as_bytes(), |caps: &bytes::Captures| {
+                        expand_cased(&pieces, |text, dst| {
+                            let mut expanded = Vec::new();
+                            caps.expand(text.as_bytes(), &mut expanded);
+                            dst.push_str(&String::from_utf8_lossy(&expanded));
+                        })
+                        .into_bytes()
+                    }),
+                    None => re.replace_all(line.as_bytes(), replacement.as_bytes()),
+                };
+                (String::from_utf8_lossy(&replaced).into_owned(), count)
+            }
+            Pattern::Literal { needle, case_sensitive: true } if !needle.is_empty() => {
+                (line.replace(needle.as_str(), replacement), line.matches(needle.as_str()).count())
+            }
+            _ => splice(line, &self.find_spans(line), replacement),
+        }
+    }
+
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        match self {
+            Pattern::Literal { needle, .. } if needle.is_empty() => Vec::new(),
+            Pattern::Literal { needle, case_sensitive: true } => {
+                line.match_indices(needle.as_str())
+                    .map(|(i, m)| i..i + m.len())
+                    .collect()
+            }
+            Pattern::Literal { needle, case_sensitive: false } => {
+                // Lowercasing can change a character's length, so remember
+                // where each folded byte came from in the original line.
+                let mut folded = String::with_capacity(line.len());
+                let mut origin = Vec::with_capacity(line.len() + 1);
+                for (i, c) in line.char_indices() {
+                    for lower in c.to_lowercase() {
+                        folded.push(lower);
+                        origin.resize(folded.len(), i);
+                    }
+                }
+                origin.push(line.len());
+
+                folded.match_indices(needle.as_str())
+                      .map(|(i, m)| origin[i]..origin[i + m.len()])
+                      .collect()
+            }
+            Pattern::Regex(re) => re.find_iter(line).map(|m| m.range()).collect(),
+            Pattern::BytesRegex(re) => re.find_iter(line.as_bytes()).map(|m| whole_chars(line, m.range())).collect(),
+        }
+    }
+
+    /// Like `find_spans`, but looks for the next match one character after
+    /// the start of the last rather than after its end, so matches can
+    /// overlap: `aa` is found 3 times in `aaaa`, not twice.
+    fn find_overlapping(&self, line: &str) -> Vec<Range<usize>> {
+        let mut spans = Vec::new();
+        let mut start = 0;
+        while let Some(span) = self.find_at(line, start) {
+            start = line[span.start..].chars().next().map_or(line.len() + 1, |c| span.start + c.len_utf8());
+            spans.push(span);
+            if start > line.len() {
+                break;
+            }
+        }
+        spans
+    }
+
+    /// The first match in `line` starting at or after byte `start`.
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        match self {
+            Pattern::Literal { needle, .. } if needle.is_empty() => None,
+            Pattern::Literal { needle, case_sensitive: true } => {
+                line[start..].find(needle.as_str()).map(|i| start + i..start + i + needle.len())
+            }
+            Pattern::Literal { .. } => {
+                let span = self.find_spans(&line[start..]).into_iter().next()?;
+                Some(start + span.start..start + span.end)
+            }
+            Pattern::Regex(re) => re.find_at(line, start).map(|m| m.range()),
+            Pattern::BytesRegex(re) => re.find_at(line.as_bytes(), start).map(|m| whole_chars(line, m.range())),
+        }
+    }
+}
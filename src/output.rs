@@ -0,0 +1,227 @@
+use std::io::{self, Write};
+use std::ops::{Range, RangeInclusive};
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::{file, ContextLine};
+
+const HIGHLIGHT_START: &str = "\x1b[1;31m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
+
+/// The longest bar a histogram draws; longer ones are scaled down to fit.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Writes through to another writer with newlines and `terminator` swapped
+/// as `file::swap_newlines` does, so lines come out ending in `terminator`.
+pub struct SwapNewlines<'a, W: Write> {
+    inner: &'a mut W,
+    terminator: u8,
+}
+
+impl<'a, W: Write> SwapNewlines<'a, W> {
+    pub fn new(inner: &'a mut W, terminator: u8) -> SwapNewlines<'a, W> {
+        SwapNewlines { inner, terminator }
+    }
+}
+
+impl<W: Write> Write for SwapNewlines<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let swapped: Vec<u8> = buf.iter().map(|&b| file::swap_newline(b, self.terminator)).collect();
+        self.inner.write_all(&swapped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `text` as an OSC 8 hyperlink to `url`, which terminals that support them
+/// make clickable.
+pub fn hyperlink(text: &str, url: &str) -> String {
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+}
+
+/// Wraps each byte range of `line` in `spans` in ANSI color escapes. Spans
+/// must be sorted and non-overlapping.
+pub fn highlight(line: &str, spans: &[Range<usize>]) -> String {
+    let mut highlighted = String::with_capacity(line.len());
+    let mut last = 0;
+
+    for span in spans {
+        highlighted.push_str(&line[last..span.start]);
+        highlighted.push_str(HIGHLIGHT_START);
+        highlighted.push_str(&line[span.clone()]);
+        highlighted.push_str(HIGHLIGHT_END);
+        last = span.end;
+    }
+    highlighted.push_str(&line[last..]);
+
+    highlighted
+}
+
+/// A line of `^` under the `spans` of `line`, for `--annotate`. Characters
+/// take the columns a terminal gives them, two for wide ones, and tabs are
+/// kept as tabs, so the marks line up under the matches. Trailing blanks are
+/// left off.
+pub fn carets(line: &str, spans: &[Range<usize>]) -> String {
+    let mut marks = String::new();
+    for (i, c) in line.char_indices() {
+        let width = c.width().unwrap_or(0);
+        if spans.iter().any(|span| span.contains(&i)) {
+            marks.extend(std::iter::repeat_n('^', width));
+        } else if c == '\t' {
+            marks.push('\t');
+        } else {
+            marks.extend(std::iter::repeat_n(' ', width));
+        }
+    }
+    marks.trim_end().to_string()
+}
+
+/// Breaks `text` into rows of at most `width` visible characters, counting
+/// `indent` columns already used on the first row, and indents the following
+/// rows by `indent` so they line up under the first. Color escapes take up
+/// no columns.
+pub fn wrap(text: &str, indent: usize, width: usize) -> String {
+    let columns = width.saturating_sub(indent).max(1);
+    let mut wrapped = String::with_capacity(text.len());
+    let mut used = 0;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            wrapped.push(c);
+            for c in chars.by_ref() {
+                wrapped.push(c);
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            continue;
+        }
+
+        if used == columns {
+            wrapped.push('\n');
+            wrapped.extend(std::iter::repeat_n(' ', indent));
+            used = 0;
+        }
+        wrapped.push(c);
+        used += 1;
+    }
+
+    wrapped
+}
+
+/// Prints context output: matches as `num:line`, context as `num~line`, and
+/// `--` between groups that aren't adjacent in the file.
+pub fn write_context<W: Write>(out: &mut W, lines: &[ContextLine], color: bool) -> io::Result<()> {
+    write_context_wrapped(out, lines, color, None, false, false)
+}
+
+/// `write_context`, wrapping lines longer than `width` as `wrap` does. With
+/// `squeeze_blank`, a blank line straight after another is left out, and
+/// with `annotate` each match is followed by its `carets`.
+pub fn write_context_wrapped<W: Write>(out: &mut W, lines: &[ContextLine], color: bool, width: Option<usize>, squeeze_blank: bool, annotate: bool) -> io::Result<()> {
+    let mut prev: Option<&ContextLine> = None;
+
+    for line in lines {
+        let mut squeezed = false;
+        if let Some(prev) = prev {
+            if line.line_num > prev.line_num + 1 {
+                writeln!(out, "--")?;
+            } else {
+                squeezed = squeeze_blank && line.line.trim().is_empty() && prev.line.trim().is_empty();
+            }
+        }
+        prev = Some(line);
+        if squeezed {
+            continue;
+        }
+
+        let separator = if line.is_match { ':' } else { '~' };
+        let text = if color {
+            highlight(line.line, &line.spans)
+        } else {
+            line.line.to_string()
+        };
+        let prefix = format!("{}{}", line.line_num, separator);
+        match width {
+            Some(width) => writeln!(out, "{}{}", prefix, wrap(&text, prefix.len(), width))?,
+            None => writeln!(out, "{}{}", prefix, text)?,
+        }
+        if annotate && line.is_match && !line.spans.is_empty() {
+            writeln!(out, "{}{}", " ".repeat(prefix.len()), carets(line.line, &line.spans))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits context output into its groups: runs of lines that are adjacent
+/// in the file.
+pub fn split_groups<'a, 'b>(lines: &'b [ContextLine<'a>]) -> Vec<&'b [ContextLine<'a>]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for i in 1..=lines.len() {
+        if i == lines.len() || lines[i].line_num > lines[i - 1].line_num + 1 {
+            if start < i {
+                groups.push(&lines[start..i]);
+            }
+            start = i;
+        }
+    }
+    groups
+}
+
+/// Prints each group as `write_context` does, with `--` between groups even
+/// when they overlap or touch.
+pub fn write_context_groups<W: Write>(out: &mut W, groups: &[Vec<ContextLine>], color: bool, width: Option<usize>, squeeze_blank: bool, annotate: bool) -> io::Result<()> {
+    for (i, group) in groups.iter().enumerate() {
+        if i > 0 {
+            writeln!(out, "--")?;
+        }
+        write_context_wrapped(out, group, color, width, squeeze_blank, annotate)?;
+    }
+
+    Ok(())
+}
+
+/// Joins numbered `lines` into one quoted excerpt: the lines trimmed and
+/// separated by spaces, with `...` wherever the line numbers jump. Blank lines
+/// add nothing.
+pub fn snippet<'a, I: IntoIterator<Item = (usize, &'a str)>>(lines: I) -> String {
+    let mut groups: Vec<Vec<&str>> = Vec::new();
+    let mut prev_line_num = None;
+    for (line_num, line) in lines {
+        if prev_line_num.is_none_or(|prev| line_num > prev + 1) {
+            groups.push(Vec::new());
+        }
+        prev_line_num = Some(line_num);
+        if let (Some(group), false) = (groups.last_mut(), line.trim().is_empty()) {
+            group.push(line.trim());
+        }
+    }
+
+    let groups: Vec<String> = groups.iter().filter(|group| !group.is_empty()).map(|group| group.join(" ")).collect();
+    format!("\"{}\"", groups.join(" ... "))
+}
+
+/// Prints `buckets` as rows like `11-20: ####  (4)`, with a `#` per line
+/// counted unless the biggest count is over `HISTOGRAM_WIDTH`, in which case
+/// bars are scaled to that width. Any nonzero count gets at least one `#`.
+pub fn write_histogram<W: Write>(out: &mut W, buckets: &[(RangeInclusive<usize>, usize)]) -> io::Result<()> {
+    let max = buckets.iter().map(|&(_, count)| count).max().unwrap_or(0);
+    let labels: Vec<String> = buckets.iter()
+                                     .map(|(range, _)| format!("{}-{}", range.start(), range.end()))
+                                     .collect();
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+    let bar_width = max.min(HISTOGRAM_WIDTH);
+
+    for (label, &(_, count)) in labels.iter().zip(buckets) {
+        let bar = if max > HISTOGRAM_WIDTH { (count * HISTOGRAM_WIDTH).div_ceil(max) } else { count };
+        writeln!(out, "{:>label_width$}: {:<bar_width$}  ({})", label, "#".repeat(bar), count)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{Config, ConfigError};
+
+const FILE_NAME: &str = ".minigreprc";
+
+/// Defaults read from a `.minigreprc`. Keys mirror the command-line flags,
+/// and each one set is applied as if its flag had been given before the
+/// real arguments, so the flags win.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RcFile {
+    ignore_case: Option<bool>,
+    context: Option<usize>,
+    before_context: Option<usize>,
+    after_context: Option<usize>,
+    recursive: Option<bool>,
+    regex: Option<bool>,
+    color: Option<String>,
+    sort_lines: Option<String>,
+    and_not: Option<Vec<String>>,
+}
+
+/// The `.minigreprc` in the current directory, or else the one in `$HOME`.
+pub fn find() -> Option<PathBuf> {
+    let home = env::var_os("HOME").map(|home| Path::new(&home).join(FILE_NAME));
+    Some(PathBuf::from(FILE_NAME)).into_iter()
+                                  .chain(home)
+                                  .find(|path| path.is_file())
+}
+
+pub fn load(path: &Path) -> Result<RcFile, ConfigError> {
+    let invalid = |msg: String| ConfigError::ConfigFile(format!("{}: {}", path.display(), msg));
+    let contents = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    toml::from_str(&contents).map_err(|err| invalid(err.message().to_string()))
+}
+
+impl RcFile {
+    pub fn apply(&self, config: &mut Config) -> Result<(), ConfigError> {
+        if self.ignore_case == Some(true) {
+            config.case_sensitive = false;
+        }
+
+        let mut flags = Vec::new();
+        if let Some(n) = self.context {
+            flags.push(format!("--context={}", n));
+        }
+        if let Some(n) = self.before_context {
+            flags.push(format!("--before-context={}", n));
+        }
+        if let Some(n) = self.after_context {
+            flags.push(format!("--after-context={}", n));
+        }
+        if self.recursive == Some(true) {
+            flags.push("--recursive".to_string());
+        }
+        if self.regex == Some(true) {
+            flags.push("--regex".to_string());
+        }
+        if let Some(when) = &self.color {
+            flags.push(format!("--color={}", when));
+        }
+        if let Some(key) = &self.sort_lines {
+            flags.push(format!("--sort-lines={}", key));
+        }
+        for pattern in self.and_not.iter().flatten() {
+            flags.push(format!("--and-not={}", pattern));
+        }
+
+        for flag in flags {
+            config.apply_option(&flag)?;
+        }
+        Ok(())
+    }
+}
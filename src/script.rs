@@ -0,0 +1,408 @@
+//! A small expression language for `--script`, evaluated against each line.
+//!
+//! ```text
+//! line.len() > 10 && line.contains("error")
+//! !line.to_lower().starts_with("debug") || line.ends_with("!")
+//! ```
+//!
+//! Values are booleans, integers and strings; `line` is the line being
+//! matched. Strings have `len`, `contains`, `starts_with`, `ends_with`,
+//! `to_lower`, `to_upper` and `trim` methods, and values of the same type
+//! compare with `==`, `!=`, `<`, `<=`, `>` and `>=`. There's nothing that can
+//! touch the outside world and no loops. Expressions may nest only
+//! `MAX_DEPTH` deep, and a line that takes longer than `LINE_BUDGET` to
+//! evaluate, however long it is, doesn't match.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::{Duration, Instant};
+
+/// How deeply an expression may nest, counting brackets, `!`, `-`, method
+/// calls and arguments, and each `&&` or `||` in a row. Deeper scripts are
+/// refused rather than overflowing the stack when parsed or evaluated.
+const MAX_DEPTH: usize = 100;
+
+/// How long a script may spend on one line before giving up on it.
+const LINE_BUDGET: Duration = Duration::from_millis(100);
+
+/// A checked script, ready to evaluate.
+#[derive(Debug, Clone)]
+pub struct Script {
+    expr: Expr,
+}
+
+impl Script {
+    /// Parses and type-checks `source`, which must evaluate to a boolean.
+    pub fn new(source: &str) -> Result<Script, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0, depth: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {}", token));
+        }
+
+        match expr.check()? {
+            Type::Bool => Ok(Script { expr }),
+            ty => Err(format!("script must be a boolean, not {}", ty)),
+        }
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.is_match_within(line, LINE_BUDGET)
+    }
+
+    /// Whether the script is true for `line`, or `false` if it can't tell
+    /// within `budget`.
+    pub fn is_match_within(&self, line: &str, budget: Duration) -> bool {
+        matches!(self.expr.eval(line, Instant::now() + budget), Some(Value::Bool(true)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Str(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Int(n) => write!(f, "`{}`", n),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Op(op) => write!(f, "`{}`", op),
+        }
+    }
+}
+
+/// Operators, longest first so `<=` isn't read as `<` then `=`.
+const OPS: [&str; 14] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", ".", ",", "-"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(Token::Str(string_literal(&mut chars)?));
+        } else if c.is_ascii_digit() {
+            let digits = take_while(&mut chars, |c| c.is_ascii_digit());
+            tokens.push(Token::Int(digits.parse().map_err(|_| format!("number too big: {}", digits))?));
+        } else if c.is_alphabetic() || c == '_' {
+            tokens.push(Token::Ident(take_while(&mut chars, |c| c.is_alphanumeric() || c == '_')));
+        } else {
+            let rest: String = chars.clone().take(2).collect();
+            let op = OPS.iter()
+                        .find(|op| rest.starts_with(*op))
+                        .ok_or_else(|| format!("unexpected `{}`", c))?;
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push(Token::Op(op));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn take_while<F: Fn(char) -> bool>(chars: &mut Peekable<Chars>, keep: F) -> String {
+    let mut taken = String::new();
+    while let Some(&c) = chars.peek().filter(|&&c| keep(c)) {
+        taken.push(c);
+        chars.next();
+    }
+    taken
+}
+
+/// Reads the rest of a `"`-quoted string, allowing `\"` and `\\` inside.
+fn string_literal(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some(c @ ('"' | '\\')) => s.push(c),
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                _ => return Err("unknown escape in string".to_string()),
+            },
+            Some(c) => s.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Line,
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(&'static str, Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, String, Vec<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// How deeply nested the expression being parsed is.
+    depth: usize,
+}
+
+impl Parser {
+    /// Goes a level deeper into the expression; the caller restores `depth`
+    /// once it's parsed that level.
+    fn nest(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("script nests more than {} deep", MAX_DEPTH));
+        }
+        Ok(())
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.tokens.get(self.pos), Some(Token::Op(found)) if *found == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(match self.tokens.get(self.pos) {
+                Some(token) => format!("expected `{}`, found {}", op, token),
+                None => format!("expected `{}` at end of script", op),
+            })
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            self.nest()?;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            self.nest()?;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            self.nest()?;
+            let expr = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+
+        let left = self.postfix()?;
+        for op in ["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(op) {
+                return Ok(Expr::Compare(op, Box::new(left), Box::new(self.postfix()?)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut expr = self.primary()?;
+        while self.eat(".") {
+            self.nest()?;
+            let method = match self.tokens.get(self.pos) {
+                Some(Token::Ident(name)) => name.clone(),
+                _ => return Err("expected a method name after `.`".to_string()),
+            };
+            self.pos += 1;
+
+            self.expect("(")?;
+            let mut args = Vec::new();
+            if !self.eat(")") {
+                loop {
+                    args.push(self.or()?);
+                    if self.eat(")") {
+                        break;
+                    }
+                    self.expect(",")?;
+                }
+            }
+            expr = Expr::Call(Box::new(expr), method, args);
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("unexpected end of script")?;
+        self.pos += 1;
+
+        match token {
+            Token::Int(n) => Ok(Expr::Int(n)),
+            Token::Str(s) => Ok(Expr::Str(s)),
+            Token::Ident(name) => match name.as_str() {
+                "line" => Ok(Expr::Line),
+                "true" => Ok(Expr::Bool(true)),
+                "false" => Ok(Expr::Bool(false)),
+                _ => Err(format!("unknown name `{}`", name)),
+            },
+            Token::Op("-") => {
+                self.nest()?;
+                let negated = match self.primary()? {
+                    Expr::Int(n) => Expr::Int(-n),
+                    _ => return Err("`-` must come before a number".to_string()),
+                };
+                self.depth -= 1;
+                Ok(negated)
+            }
+            Token::Op("(") => {
+                self.nest()?;
+                let expr = self.or()?;
+                self.expect(")")?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            token => Err(format!("unexpected {}", token)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Bool,
+    Int,
+    Str,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Type::Bool => "a boolean",
+            Type::Int => "an integer",
+            Type::Str => "a string",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Value {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl Expr {
+    /// The type this evaluates to, or why it's ill-typed.
+    fn check(&self) -> Result<Type, String> {
+        let want = |expr: &Expr, ty: Type, what: &str| match expr.check()? {
+            found if found == ty => Ok(()),
+            found => Err(format!("{} needs {}, not {}", what, ty, found)),
+        };
+
+        match self {
+            Expr::Bool(_) => Ok(Type::Bool),
+            Expr::Int(_) => Ok(Type::Int),
+            Expr::Str(_) | Expr::Line => Ok(Type::Str),
+            Expr::Not(expr) => want(expr, Type::Bool, "`!`").map(|_| Type::Bool),
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                want(left, Type::Bool, "`&&` and `||`")?;
+                want(right, Type::Bool, "`&&` and `||`")?;
+                Ok(Type::Bool)
+            }
+            Expr::Compare(op, left, right) => {
+                let ty = left.check()?;
+                want(right, ty, &format!("comparing {} with `{}`", ty, op))?;
+                Ok(Type::Bool)
+            }
+            Expr::Call(receiver, method, args) => {
+                want(receiver, Type::Str, &format!("`.{}()`", method))?;
+                let (params, returns): (&[Type], Type) = match method.as_str() {
+                    "len" => (&[], Type::Int),
+                    "contains" | "starts_with" | "ends_with" => (&[Type::Str], Type::Bool),
+                    "to_lower" | "to_upper" | "trim" => (&[], Type::Str),
+                    _ => return Err(format!("unknown method `{}`", method)),
+                };
+                if args.len() != params.len() {
+                    return Err(format!("`.{}()` takes {} argument(s)", method, params.len()));
+                }
+                for (arg, &ty) in args.iter().zip(params) {
+                    want(arg, ty, &format!("`.{}()`", method))?;
+                }
+                Ok(returns)
+            }
+        }
+    }
+
+    /// Evaluates a checked expression, or gives up with `None` once past
+    /// `deadline`.
+    fn eval(&self, line: &str, deadline: Instant) -> Option<Value> {
+        if Instant::now() > deadline {
+            return None;
+        }
+        let text = |expr: &Expr| match expr.eval(line, deadline)? {
+            Value::Str(s) => Some(s),
+            value => unreachable!("checked to be a string: {:?}", value),
+        };
+
+        Some(match self {
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Int(n) => Value::Int(*n),
+            Expr::Str(s) => Value::Str(s.clone()),
+            Expr::Line => Value::Str(line.to_string()),
+            Expr::Not(expr) => Value::Bool(expr.eval(line, deadline)? != Value::Bool(true)),
+            Expr::And(left, right) => {
+                Value::Bool(left.eval(line, deadline)? == Value::Bool(true) && right.eval(line, deadline)? == Value::Bool(true))
+            }
+            Expr::Or(left, right) => {
+                Value::Bool(left.eval(line, deadline)? == Value::Bool(true) || right.eval(line, deadline)? == Value::Bool(true))
+            }
+            Expr::Compare(op, left, right) => {
+                let ordering = left.eval(line, deadline)?.cmp(&right.eval(line, deadline)?);
+                Value::Bool(match *op {
+                    "==" => ordering == Ordering::Equal,
+                    "!=" => ordering != Ordering::Equal,
+                    "<" => ordering == Ordering::Less,
+                    "<=" => ordering != Ordering::Greater,
+                    ">" => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                })
+            }
+            Expr::Call(receiver, method, args) => {
+                let s = text(receiver)?;
+                match method.as_str() {
+                    "len" => Value::Int(s.chars().count() as i64),
+                    "contains" => Value::Bool(s.contains(&text(&args[0])?)),
+                    "starts_with" => Value::Bool(s.starts_with(&text(&args[0])?)),
+                    "ends_with" => Value::Bool(s.ends_with(&text(&args[0])?)),
+                    "to_lower" => Value::Str(s.to_lowercase()),
+                    "to_upper" => Value::Str(s.to_uppercase()),
+                    _ => Value::Str(s.trim().to_string()),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn minigrep(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_minigrep"))
+        .args(args)
+        .output()
+        .expect("failed to run minigrep")
+}
+
+#[test]
+fn missing_file_reports_json_error() {
+    let output = minigrep(&["--error-format=json", "to", "no-such-file.txt"]);
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!("io", error["kind"]);
+    assert!(error["error"].as_str().unwrap().contains("No such file"));
+}
+
+#[test]
+fn missing_file_reports_text_error_by_default() {
+    let output = minigrep(&["to", "no-such-file.txt"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Application error: "));
+}
+
+#[test]
+fn passthru_prints_every_line_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_minigrep"))
+        .args(["--passthru", "--color=always", "needle", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"hay\nneedle here\nmore hay\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(
+        "1~hay\n2:\x1b[1;31mneedle\x1b[0m here\n3~more hay\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+}
+
+#[test]
+fn quiet_reports_matches_through_the_exit_status() {
+    let found = minigrep(&["--quiet", "needle", "Cargo.toml"]);
+    assert_eq!(Some(1), found.status.code());
+    assert!(found.stdout.is_empty());
+
+    let found = minigrep(&["-q", "minigrep", "Cargo.toml"]);
+    assert_eq!(Some(0), found.status.code());
+    assert!(found.stdout.is_empty());
+
+    let failed = minigrep(&["-q", "minigrep", "no-such-file.txt"]);
+    assert_eq!(Some(2), failed.status.code());
+}
+
+#[test]
+fn count_prints_zero_when_nothing_matches() {
+    let output = minigrep(&["-c", "no-such-text-anywhere", "Cargo.toml"]);
+    assert!(output.status.success());
+    assert_eq!("0\n", String::from_utf8(output.stdout).unwrap());
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn fail_if_matches_sets_the_exit_status_by_match_count() {
+    let output = minigrep(&["--fail-if-matches", "minigrep", "Cargo.toml"]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(!output.stdout.is_empty());
+
+    let output = minigrep(&["--fail-if-matches=2", "minigrep", "Cargo.toml"]);
+    assert_eq!(Some(0), output.status.code());
+
+    let output = minigrep(&["--fail-if-matches", "no-such-text-anywhere", "Cargo.toml"]);
+    assert_eq!(Some(0), output.status.code());
+}
+
+#[test]
+fn fail_if_matches_counts_stdin_from_the_one_search() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_minigrep"))
+        .args(["--fail-if-matches", "foo", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"foo\nbar\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("foo\n", String::from_utf8(output.stdout).unwrap());
+}
+
+#[test]
+fn fail_if_no_matches_sets_the_exit_status_when_nothing_matches() {
+    let output = minigrep(&["--fail-if-no-matches", "no-such-text-anywhere", "Cargo.toml"]);
+    assert_eq!(Some(1), output.status.code());
+
+    let output = minigrep(&["--fail-if-no-matches", "minigrep", "Cargo.toml"]);
+    assert_eq!(Some(0), output.status.code());
+}
+
+#[test]
+fn no_color_only_turns_off_automatic_colors() {
+    let search = |color: &str| {
+        Command::new(env!("CARGO_BIN_EXE_minigrep"))
+            .args([color, "minigrep", "Cargo.toml"])
+            .env("NO_COLOR", "1")
+            .output()
+            .expect("failed to run minigrep")
+    };
+
+    assert!(!String::from_utf8(search("--color=auto").stdout).unwrap().contains('\x1b'));
+    assert!(String::from_utf8(search("--color=always").stdout).unwrap().contains("\x1b[1;31mminigrep\x1b[0m"));
+}